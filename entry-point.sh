@@ -6,6 +6,7 @@ echo "registering first user"
 sleep 1
 masacarri_cli adduser ${MASACARRI_USER}
 printf "${MASACARRI_PASSWORD}\n${MASACARRI_PASSWORD}\n" | masacarri_cli passwd ${MASACARRI_USER}
+masacarri_cli grant ${MASACARRI_USER} owner
 
 echo "server starting"
 sleep 1
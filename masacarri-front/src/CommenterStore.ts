@@ -0,0 +1,60 @@
+import { defineStore } from "pinia";
+import type { Commenter, CommenterProvider } from "./models";
+import { app_fetch, commenter_token_key } from "./utils";
+
+type CommenterLoginMessage = {
+    type: 'masacarri-commenter',
+    token: string,
+    commenter: Commenter,
+};
+
+export const useCommenterStore = defineStore({
+    id: "commenter",
+    state: (): {
+        provider: CommenterProvider,
+        commenter: Commenter | null,
+    } => {
+        return {
+            provider: { enabled: false },
+            commenter: null,
+        }
+    },
+    actions: {
+        load() {
+            return app_fetch(`/api/commenter/provider`)
+                .then((res: CommenterProvider) => {
+                    this.provider = res;
+                    if (!res.enabled || !localStorage.getItem(commenter_token_key)) {
+                        return;
+                    }
+                    return app_fetch(`/api/commenter/me`)
+                        .then((res: Commenter) => {
+                            this.commenter = res;
+                        })
+                        .catch(() => {
+                            localStorage.removeItem(commenter_token_key);
+                        });
+                });
+        },
+        signIn() {
+            const api_origin = new URL(import.meta.env.VITE_API_BASE_URL || location.origin, location.href).origin;
+            const listener = (ev: MessageEvent<CommenterLoginMessage>) => {
+                if (ev.origin !== api_origin || ev.data?.type !== 'masacarri-commenter') {
+                    return;
+                }
+                window.removeEventListener('message', listener);
+                localStorage.setItem(commenter_token_key, ev.data.token);
+                this.commenter = ev.data.commenter;
+            };
+            window.addEventListener('message', listener);
+            window.open(import.meta.env.VITE_API_BASE_URL + `/api/commenter/login`, 'masacarri-sign-in', 'width=500,height=700');
+        },
+        signOut() {
+            return app_fetch(`/api/commenter/logout`, "POST")
+                .finally(() => {
+                    localStorage.removeItem(commenter_token_key);
+                    this.commenter = null;
+                });
+        },
+    },
+});
@@ -1,5 +1,5 @@
 import { defineStore } from "pinia";
-import type { Comment, NewCommentRequest } from "./models";
+import type { Comment, CommentSort, NewCommentRequest, ReactionResult } from "./models";
 import type { Dayjs } from "dayjs";
 import dayjs from "dayjs";
 import { app_fetch } from "./utils";
@@ -9,10 +9,18 @@ export type ShowingComment = {
     name: string,
     site_url?: string,
     date: Dayjs,
+    edited_date?: Dayjs,
     content: string,
     is_spam: boolean,
+    is_pinned: boolean,
+    is_locked: boolean,
+    verified: boolean,
+    author: boolean,
+    avatar_url?: string,
     parent: string | undefined,
     count_replies: number,
+    reactions: Record<string, number>,
+    reacted: string[],
     children?: ShowingComment[],
 };
 
@@ -28,10 +36,18 @@ function toShowComment(raw: Comment): ShowingComment {
         name: raw.display_name,
         site_url: raw.site_url,
         date: dayjs(raw.created_time),
+        edited_date: raw.edited_time ? dayjs(raw.edited_time) : undefined,
         content: raw.content,
         is_spam: raw.is_spam ? true : false,
+        is_pinned: raw.is_pinned ? true : false,
+        is_locked: raw.is_locked ? true : false,
+        verified: raw.verified,
+        author: raw.author,
+        avatar_url: raw.avatar_url,
         parent: raw.reply_to,
         count_replies: raw.count_replies,
+        reactions: raw.reactions ?? {},
+        reacted: [],
         children: [],
     };
 }
@@ -41,6 +57,11 @@ function latestPageIndex(comments_count: number, comments_per_page: number) {
     return Math.max(1, index);
 }
 
+// the latest comments are on the last page only when the oldest come first
+function defaultPageIndex(comments_count: number, comments_per_page: number, sort: CommentSort) {
+    return sort === 'oldest' ? latestPageIndex(comments_count, comments_per_page) : 1;
+}
+
 export const useCommentsStore = defineStore({
     id: "showing_comments",
     state: (): {
@@ -55,6 +76,8 @@ export const useCommentsStore = defineStore({
         comment_shows_context: string | undefined,
         page_id: string | undefined,
         page_loading: boolean,
+        reaction_kinds: string[],
+        sort: CommentSort,
     } => {
         return {
             comments: new Map(),
@@ -72,6 +95,8 @@ export const useCommentsStore = defineStore({
             comment_shows_context: undefined,
             page_id: undefined,
             page_loading: true,
+            reaction_kinds: [],
+            sort: 'oldest',
         }
     },
     getters: {
@@ -93,7 +118,7 @@ export const useCommentsStore = defineStore({
                         resolve(null);
                     })
                         .then(() => {
-                            return this.commentCountReload(page_id);
+                            return Promise.all([this.commentCountReload(page_id), this.loadReactionKinds()]);
                         })
                         .then((res) => {
                             this.page_id = page_id;
@@ -108,9 +133,23 @@ export const useCommentsStore = defineStore({
                     this.page_loading = false;
                 });
         },
+        loadReactionKinds() {
+            return app_fetch(`/api/reactions`)
+                .then((res) => {
+                    this.reaction_kinds = res.kinds;
+                });
+        },
+        toggleReaction(comment: ShowingComment, kind: string) {
+            const method = comment.reacted.includes(kind) ? "DELETE" : "PUT";
+            return app_fetch(`/api/pages/${this.page_id}/comments/${comment.comment_id}/reactions/${kind}`, method)
+                .then((res: ReactionResult) => {
+                    comment.reactions = res.reactions;
+                    comment.reacted = res.reacted;
+                });
+        },
         loadComment(index: number | null = null, comment_per_page: number = 7) {
-            const realIndex = index ? index : latestPageIndex(this.comments_count, comment_per_page);
-            return app_fetch(`/api/pages/${this.page_id}/comments?index=${realIndex}&num=${comment_per_page}`)
+            const realIndex = index ? index : defaultPageIndex(this.comments_count, comment_per_page, this.sort);
+            return app_fetch(`/api/pages/${this.page_id}/comments?index=${realIndex}&num=${comment_per_page}&sort=${this.sort}`)
                 .then((res: Comment[]) => {
                     this.comments.clear();
                     this.comment_showlist.length = 0;
@@ -151,7 +190,7 @@ export const useCommentsStore = defineStore({
                                 tmp.count_replies = res.count;
                                 this.comments.set(target_comment.id, tmp);
                             }
-                            index = index ? index : latestPageIndex(res.count, comment_per_page);
+                            index = index ? index : defaultPageIndex(res.count, comment_per_page, this.sort);
                             this.sub_pagination = {
                                 index: index,
                                 item_count: res.count,
@@ -161,7 +200,7 @@ export const useCommentsStore = defineStore({
                         });
                 })
                 .then((target_comment) => {
-                    return app_fetch(`/api/pages/${this.page_id}/comments?replyto=${replyto}&index=${index}&num=${comment_per_page}`)
+                    return app_fetch(`/api/pages/${this.page_id}/comments?replyto=${replyto}&index=${index}&num=${comment_per_page}&sort=${this.sort}`)
                         .then((res: Comment[]) => {
                             this.comments.clear();
                             this.comment_showlist.length = 0;
@@ -200,7 +239,7 @@ export const useCommentsStore = defineStore({
                 .then((target_comment) => {
                     return app_fetch(`/api/pages/${this.page_id}/comments_count?contextof=${contextof}`)
                         .then((res) => {
-                            index = index ? index : latestPageIndex(res.count, comment_per_page);
+                            index = index ? index : defaultPageIndex(res.count, comment_per_page, this.sort);
                             this.sub_pagination = {
                                 index: index,
                                 item_count: res.count,
@@ -210,7 +249,7 @@ export const useCommentsStore = defineStore({
                         });
                 })
                 .then((target_comment) => {
-                    return app_fetch(`/api/pages/${this.page_id}/comments?contextof=${contextof}&index=${index}&num=${comment_per_page}`)
+                    return app_fetch(`/api/pages/${this.page_id}/comments?contextof=${contextof}&index=${index}&num=${comment_per_page}&sort=${this.sort}`)
                         .then((res: Comment[]) => {
                             this.comments.clear();
                             this.comment_showlist.length = 0;
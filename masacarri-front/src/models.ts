@@ -5,9 +5,35 @@ export type Comment = {
     display_name: string,
     site_url?: string,
     content: string,
+    content_html: string,
     is_spam?: boolean,
+    is_deleted?: boolean,
+    is_pinned?: boolean,
+    is_locked?: boolean,
     count_replies: number,
     created_time: string,
+    status: 'pending' | 'approved' | 'rejected',
+    verified: boolean,
+    author: boolean,
+    avatar_url?: string,
+    reactions?: Record<string, number>,
+    edited: boolean,
+    edited_time?: string,
+};
+
+export type MarkCommentRequest = {
+    spam?: boolean,
+    pinned?: boolean,
+    locked?: boolean,
+};
+
+export type DeleteMode = 'tombstone' | 'purge';
+
+export type CommentSort = 'oldest' | 'newest' | 'most_replied' | 'top';
+
+export type ReactionResult = {
+    reactions: Record<string, number>,
+    reacted: string[],
 };
 
 export type Page = {
@@ -15,6 +41,29 @@ export type Page = {
     title: string,
     page_url: string,
     published: boolean,
+    premoderated: boolean,
+    site_id: string,
+    allow_anonymous: boolean,
+    archived: boolean,
+};
+
+export type Site = {
+    id: string,
+    name: string,
+    allowed_origins: string[],
+    mail_from?: string,
+    premoderated: boolean,
+};
+
+export type Commenter = {
+    id: string,
+    display_name: string,
+    avatar_url?: string,
+};
+
+export type CommenterProvider = {
+    enabled: boolean,
+    name?: string,
 };
 
 export type NewCommentRequest = {
@@ -24,4 +73,25 @@ export type NewCommentRequest = {
     mail_addr?: string,
     content: string,
     delete_key?: string,
+    honeypot?: string,
 }
+
+export type Ban = {
+    id: string,
+    ip_range?: string,
+    mail_addr?: string,
+    display_name_pattern?: string,
+    reason?: string,
+    created_time: string,
+    expires_time?: string,
+    site_id: string,
+};
+
+export type User = {
+    id: string,
+    username: string,
+    display_name?: string,
+    role: 'owner' | 'admin' | 'moderator' | 'readonly',
+    disabled: boolean,
+    sites: string[],
+};
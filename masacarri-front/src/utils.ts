@@ -1,10 +1,18 @@
+export const commenter_token_key = 'masacarri-commenter-token';
+
 export function app_fetch<T>(path: string, method?: string, data?: T, is_admin?: boolean) {
+    const headers: Record<string, string> = {
+        'Content-Type': 'application/json'
+    };
+    const commenter_token = localStorage.getItem(commenter_token_key);
+    if (commenter_token) {
+        headers['X-Commenter-Token'] = commenter_token;
+    }
+
     return fetch(import.meta.env.VITE_API_BASE_URL + path, {
         method: method ?? "GET",
         body: data ? JSON.stringify(data) : undefined,
-        headers: {
-            'Content-Type': 'application/json'
-        },
+        headers: headers,
         mode: import.meta.env.DEV ? 'cors' : undefined,
         credentials: is_admin ? (import.meta.env.DEV ? 'include' : 'same-origin') : undefined,
     }).then(res => {
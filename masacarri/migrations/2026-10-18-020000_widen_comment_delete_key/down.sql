@@ -0,0 +1,2 @@
+ALTER TABLE comments ALTER COLUMN delete_key TYPE VARCHAR(32)
+  USING CASE WHEN length(delete_key) > 32 THEN '-' ELSE delete_key END;
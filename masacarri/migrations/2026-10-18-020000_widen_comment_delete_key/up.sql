@@ -0,0 +1 @@
+ALTER TABLE comments ALTER COLUMN delete_key TYPE VARCHAR(1024);
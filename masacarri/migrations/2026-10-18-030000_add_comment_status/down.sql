@@ -0,0 +1,3 @@
+ALTER TABLE pages DROP COLUMN premoderated;
+DROP INDEX comments_status_idx;
+ALTER TABLE comments DROP COLUMN status;
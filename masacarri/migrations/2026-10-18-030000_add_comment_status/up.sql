@@ -0,0 +1,4 @@
+ALTER TABLE comments ADD COLUMN status VARCHAR(16) NOT NULL DEFAULT 'approved'
+  CHECK (status IN ('pending', 'approved', 'rejected'));
+CREATE INDEX comments_status_idx ON comments (status);
+ALTER TABLE pages ADD COLUMN premoderated BOOLEAN NOT NULL DEFAULT FALSE;
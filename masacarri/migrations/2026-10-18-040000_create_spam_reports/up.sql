@@ -0,0 +1,9 @@
+CREATE TABLE spam_reports (
+  id UUID PRIMARY KEY,
+  comment_id UUID NOT NULL,
+  filter_name VARCHAR(64) NOT NULL,
+  score DOUBLE PRECISION NOT NULL,
+  reason VARCHAR(1024) NOT NULL,
+  FOREIGN KEY (comment_id) REFERENCES comments (id) ON DELETE CASCADE
+);
+CREATE INDEX spam_reports_comment_id_idx ON spam_reports (comment_id);
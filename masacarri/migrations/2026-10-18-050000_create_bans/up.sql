@@ -0,0 +1,10 @@
+CREATE TABLE bans (
+  id UUID PRIMARY KEY,
+  ip_range INET,
+  mail_addr VARCHAR(1024),
+  display_name_pattern VARCHAR(1024),
+  reason VARCHAR(1024),
+  created_time TIMESTAMP WITH TIME ZONE NOT NULL,
+  expires_time TIMESTAMP WITH TIME ZONE,
+  CHECK (ip_range IS NOT NULL OR mail_addr IS NOT NULL OR display_name_pattern IS NOT NULL)
+);
@@ -0,0 +1 @@
+ALTER TABLE comments DROP COLUMN content_html;
@@ -0,0 +1 @@
+ALTER TABLE comments ADD COLUMN content_html TEXT NOT NULL DEFAULT '';
@@ -0,0 +1,6 @@
+DROP INDEX bans_site_id_idx;
+ALTER TABLE bans DROP COLUMN site_id;
+DROP INDEX pages_site_id_idx;
+ALTER TABLE pages DROP COLUMN site_id;
+DROP TABLE site_users;
+DROP TABLE sites;
@@ -0,0 +1,28 @@
+CREATE TABLE sites (
+  id UUID PRIMARY KEY,
+  name VARCHAR(256) NOT NULL UNIQUE,
+  allowed_origins TEXT[] NOT NULL DEFAULT '{}',
+  mail_from VARCHAR(1024),
+  premoderated BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE site_users (
+  site_id UUID NOT NULL REFERENCES sites (id) ON DELETE CASCADE,
+  user_id UUID NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+  PRIMARY KEY (site_id, user_id)
+);
+
+-- everything created before sites existed belongs to the default site
+INSERT INTO sites (id, name) VALUES ('00000000-0000-0000-0000-000000000000', 'default');
+INSERT INTO site_users (site_id, user_id)
+  SELECT '00000000-0000-0000-0000-000000000000', id FROM users;
+
+ALTER TABLE pages ADD COLUMN site_id UUID NOT NULL
+  DEFAULT '00000000-0000-0000-0000-000000000000' REFERENCES sites (id);
+ALTER TABLE pages ALTER COLUMN site_id DROP DEFAULT;
+CREATE INDEX pages_site_id_idx ON pages (site_id);
+
+ALTER TABLE bans ADD COLUMN site_id UUID NOT NULL
+  DEFAULT '00000000-0000-0000-0000-000000000000' REFERENCES sites (id) ON DELETE CASCADE;
+ALTER TABLE bans ALTER COLUMN site_id DROP DEFAULT;
+CREATE INDEX bans_site_id_idx ON bans (site_id);
@@ -0,0 +1,4 @@
+-- users created before roles existed could do everything
+ALTER TABLE users ADD COLUMN role VARCHAR(16) NOT NULL DEFAULT 'owner'
+  CHECK (role IN ('owner', 'admin', 'moderator', 'readonly'));
+ALTER TABLE users ALTER COLUMN role SET DEFAULT 'readonly';
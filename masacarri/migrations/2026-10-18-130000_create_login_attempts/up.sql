@@ -0,0 +1,11 @@
+CREATE TABLE login_attempts (
+  id UUID PRIMARY KEY,
+  username VARCHAR(256) NOT NULL,
+  ip_addr INET NOT NULL,
+  result VARCHAR(16) NOT NULL CHECK (result IN ('succeeded', 'failed', 'lockedout')),
+  created_time TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX login_attempts_username_idx ON login_attempts (username, created_time);
+CREATE INDEX login_attempts_ip_addr_idx ON login_attempts (ip_addr, created_time);
+CREATE INDEX login_attempts_created_time_idx ON login_attempts (created_time);
@@ -0,0 +1,5 @@
+ALTER TABLE pages DROP COLUMN allow_anonymous;
+ALTER TABLE comments DROP COLUMN avatar_url;
+ALTER TABLE comments DROP COLUMN commenter_id;
+DROP TABLE commenter_sessions;
+DROP TABLE commenters;
@@ -0,0 +1,23 @@
+CREATE TABLE commenters (
+  id UUID PRIMARY KEY,
+  issuer VARCHAR(512) NOT NULL,
+  subject VARCHAR(256) NOT NULL,
+  display_name VARCHAR(256) NOT NULL,
+  avatar_url VARCHAR(2048),
+  created_time TIMESTAMPTZ NOT NULL,
+  last_login_time TIMESTAMPTZ NOT NULL,
+  UNIQUE (issuer, subject)
+);
+
+CREATE TABLE commenter_sessions (
+  id UUID PRIMARY KEY,
+  commenter_id UUID NOT NULL REFERENCES commenters (id) ON DELETE CASCADE,
+  token_hash VARCHAR(64) NOT NULL UNIQUE,
+  created_time TIMESTAMPTZ NOT NULL,
+  expires_time TIMESTAMPTZ NOT NULL
+);
+
+ALTER TABLE comments ADD COLUMN commenter_id UUID REFERENCES commenters (id) ON DELETE SET NULL;
+ALTER TABLE comments ADD COLUMN avatar_url VARCHAR(2048);
+
+ALTER TABLE pages ADD COLUMN allow_anonymous BOOLEAN NOT NULL DEFAULT TRUE;
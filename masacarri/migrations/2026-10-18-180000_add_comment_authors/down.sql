@@ -0,0 +1,3 @@
+ALTER TABLE comments DROP COLUMN author_user_id;
+
+ALTER TABLE users DROP COLUMN display_name;
@@ -0,0 +1,3 @@
+ALTER TABLE users ADD COLUMN display_name VARCHAR(256);
+
+ALTER TABLE comments ADD COLUMN author_user_id UUID REFERENCES users (id) ON DELETE SET NULL;
@@ -0,0 +1,8 @@
+CREATE TABLE reactions (
+  id UUID PRIMARY KEY,
+  comment_id UUID NOT NULL REFERENCES comments (id) ON DELETE CASCADE,
+  kind VARCHAR(32) NOT NULL,
+  voter VARCHAR(80) NOT NULL,
+  created_time TIMESTAMPTZ NOT NULL,
+  UNIQUE (comment_id, kind, voter)
+);
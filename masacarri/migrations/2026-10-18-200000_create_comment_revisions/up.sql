@@ -0,0 +1,13 @@
+ALTER TABLE comments ADD COLUMN edited_time TIMESTAMPTZ;
+
+CREATE TABLE comment_revisions (
+  id UUID PRIMARY KEY,
+  comment_id UUID NOT NULL REFERENCES comments (id) ON DELETE CASCADE,
+  content VARCHAR(16384) NOT NULL,
+  content_html TEXT NOT NULL,
+  created_time TIMESTAMPTZ NOT NULL,
+  replaced_time TIMESTAMPTZ NOT NULL,
+  replaced_by_user_id UUID REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX comment_revisions_comment_id ON comment_revisions (comment_id);
@@ -0,0 +1,11 @@
+CREATE TABLE audit_logs (
+  id UUID PRIMARY KEY,
+  user_id UUID REFERENCES users (id) ON DELETE SET NULL,
+  username VARCHAR(256) NOT NULL,
+  action VARCHAR(64) NOT NULL,
+  target_id UUID NOT NULL,
+  detail JSONB NOT NULL,
+  created_time TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX audit_logs_created_time_idx ON audit_logs (created_time);
@@ -0,0 +1 @@
+ALTER TABLE pages ADD COLUMN archived BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,210 @@
+use crate::auth::{AuthUser, Permission};
+use crate::db::{MainDbConnection, Pool};
+use crate::error::{AppError, AppResult};
+use crate::models::{ApiToken, User};
+use crate::schema::{api_tokens, users};
+use crate::user::is_user_disabled;
+use crate::utils::sha256_hex;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Deserialize;
+use serde_json::json;
+
+const TOKEN_PREFIX: &str = "msc_";
+const TOKEN_RANDOM_LENGTH: usize = 40;
+const MAX_TOKEN_NAME_LENGTH: usize = 256;
+
+/// Every column of `api_tokens` but the hash, in the order of `ApiToken`.
+pub const API_TOKEN_COLUMNS: (
+    api_tokens::id,
+    api_tokens::user_id,
+    api_tokens::name,
+    api_tokens::scopes,
+    api_tokens::created_time,
+    api_tokens::expires_time,
+    api_tokens::last_used_time,
+) = (
+    api_tokens::id,
+    api_tokens::user_id,
+    api_tokens::name,
+    api_tokens::scopes,
+    api_tokens::created_time,
+    api_tokens::expires_time,
+    api_tokens::last_used_time,
+);
+
+#[derive(Insertable)]
+#[table_name = "api_tokens"]
+pub struct NewApiToken {
+    id: uuid::Uuid,
+    user_id: uuid::Uuid,
+    name: String,
+    token_hash: String,
+    scopes: Vec<String>,
+    created_time: DateTime<Utc>,
+    expires_time: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiTokenRequest {
+    name: String,
+    #[serde(default)]
+    scopes: Vec<String>,
+    expires_in_days: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteApiTokenRequestPath {
+    token: uuid::Uuid,
+}
+
+/// Refuses unknown scopes instead of leaving a token without them.
+fn parse_scopes(scopes: &[String]) -> AppResult<Vec<Permission>> {
+    scopes
+        .iter()
+        .map(|s| s.parse::<Permission>().map_err(AppError::PublishableErr))
+        .collect()
+}
+
+/// Creates a token and returns it with its secret, which is not stored and cannot be shown again.
+pub fn create_token(
+    conn: &MainDbConnection,
+    tgt_user_id: uuid::Uuid,
+    r_name: &str,
+    r_scopes: &[Permission],
+    r_expires_time: Option<DateTime<Utc>>,
+) -> AppResult<(ApiToken, String)> {
+    let r_name = r_name.trim();
+    if r_name.is_empty() || r_name.chars().count() > MAX_TOKEN_NAME_LENGTH {
+        return Err(AppError::PublishableErr(format!(
+            "Token name must be 1 to {} characters.",
+            MAX_TOKEN_NAME_LENGTH
+        )));
+    }
+
+    let secret = format!(
+        "{}{}",
+        TOKEN_PREFIX,
+        Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_RANDOM_LENGTH)
+    );
+
+    let token = diesel::insert_into(api_tokens::table)
+        .values(NewApiToken {
+            id: uuid::Uuid::new_v4(),
+            user_id: tgt_user_id,
+            name: r_name.to_string(),
+            token_hash: sha256_hex(&secret),
+            scopes: r_scopes.iter().map(|p| p.scope().to_string()).collect(),
+            created_time: Utc::now(),
+            expires_time: r_expires_time,
+        })
+        .returning(API_TOKEN_COLUMNS)
+        .get_result::<ApiToken>(conn)?;
+
+    Ok((token, secret))
+}
+
+/// The user a bearer token belongs to and the token's scopes, if it is valid.
+pub fn find_token_user(
+    conn: &MainDbConnection,
+    raw_token: &str,
+) -> AppResult<Option<(User, Vec<Permission>)>> {
+    let now = Utc::now();
+    let found = api_tokens::table
+        .inner_join(users::table)
+        .filter(api_tokens::token_hash.eq(sha256_hex(raw_token)))
+        .filter(
+            api_tokens::expires_time
+                .is_null()
+                .or(api_tokens::expires_time.gt(now)),
+        )
+        .select((API_TOKEN_COLUMNS, users::all_columns))
+        .first::<(ApiToken, User)>(conn)
+        .optional()?;
+
+    let (token, user) = match found {
+        Some((_, user)) if is_user_disabled(&user) => return Ok(None),
+        Some(v) => v,
+        None => return Ok(None),
+    };
+
+    diesel::update(api_tokens::table.find(token.id))
+        .set(api_tokens::last_used_time.eq(now))
+        .execute(conn)?;
+
+    // an empty list of scopes grants everything the owner's role allows
+    let scopes = if token.scopes.is_empty() {
+        Permission::ALL.to_vec()
+    } else {
+        parse_scopes(&token.scopes)?
+    };
+
+    Ok(Some((user, scopes)))
+}
+
+pub async fn get_api_tokens(user: AuthUser, db: web::Data<Pool>) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    let result = api_tokens::table
+        .filter(api_tokens::user_id.eq(user.id))
+        .select(API_TOKEN_COLUMNS)
+        .order(api_tokens::created_time.desc())
+        .load::<ApiToken>(&conn)?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+pub async fn create_api_token(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    new_token: web::Json<CreateApiTokenRequest>,
+) -> AppResult<impl Responder> {
+    user.require_session()?;
+
+    let conn = db.get()?;
+
+    let CreateApiTokenRequest {
+        name: r_name,
+        scopes: r_scopes,
+        expires_in_days: r_expires_in_days,
+    } = new_token.into_inner();
+
+    let r_scopes = parse_scopes(&r_scopes)?;
+    for scope in &r_scopes {
+        user.require(*scope)?;
+    }
+
+    let r_expires_time = r_expires_in_days.map(|days| Utc::now() + Duration::days(days.into()));
+
+    let (token, secret) = create_token(&conn, user.id, &r_name, &r_scopes, r_expires_time)?;
+
+    Ok(HttpResponse::Created().json(json!({
+        "token": token,
+        "secret": secret,
+    })))
+}
+
+/// Users revoke their own tokens. Owners can revoke anyone's.
+pub async fn delete_api_token(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    path_param: web::Path<DeleteApiTokenRequestPath>,
+) -> AppResult<impl Responder> {
+    user.require_session()?;
+
+    let conn = db.get()?;
+
+    let mut query = diesel::delete(api_tokens::table.find(path_param.token)).into_boxed();
+    if !user.allows(Permission::ManageUsers) {
+        query = query.filter(api_tokens::user_id.eq(user.id));
+    }
+    let deleted = query.execute(&conn)?;
+
+    if deleted == 0 {
+        return Err(AppError::NotFoundErr("token not found".to_string()));
+    }
+
+    Ok(HttpResponse::NoContent())
+}
@@ -0,0 +1,104 @@
+use crate::auth::{AuthUser, Permission};
+use crate::db::{MainDbConnection, Pool};
+use crate::error::{AppError, AppResult};
+use crate::models::AuditLog;
+use crate::schema::audit_logs;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::Deserialize;
+use static_assertions::const_assert;
+
+const DEFAULT_ITEMS_PER_PAGE: u32 = 50;
+const DEFAULT_PAGE_INDEX: u32 = 1;
+const MAX_ITEMS_PER_PAGE: u32 = 256;
+
+const_assert!(DEFAULT_ITEMS_PER_PAGE <= MAX_ITEMS_PER_PAGE);
+const_assert!(DEFAULT_PAGE_INDEX == 1);
+
+#[derive(Insertable)]
+#[table_name = "audit_logs"]
+pub struct NewAuditLog {
+    id: uuid::Uuid,
+    user_id: Option<uuid::Uuid>,
+    username: String,
+    action: String,
+    target_id: uuid::Uuid,
+    detail: serde_json::Value,
+    created_time: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct GetAuditLogsQuery {
+    action: Option<String>,
+    target_id: Option<uuid::Uuid>,
+    num: Option<u32>,
+    index: Option<u32>,
+}
+
+/// Records what a user did. `detail` must not repeat removed content.
+pub fn record(
+    conn: &MainDbConnection,
+    user: &AuthUser,
+    r_action: &str,
+    r_target_id: uuid::Uuid,
+    r_detail: serde_json::Value,
+) -> AppResult<()> {
+    diesel::insert_into(audit_logs::table)
+        .values(NewAuditLog {
+            id: uuid::Uuid::new_v4(),
+            user_id: Some(user.id),
+            username: user.username.clone(),
+            action: r_action.to_string(),
+            target_id: r_target_id,
+            detail: r_detail,
+            created_time: Utc::now(),
+        })
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Newest first.
+pub async fn get_audit_logs(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    query_param: web::Query<GetAuditLogsQuery>,
+) -> AppResult<impl Responder> {
+    user.require(Permission::ManageUsers)?;
+
+    let conn = db.get()?;
+
+    let items_per_page = query_param.num.unwrap_or(DEFAULT_ITEMS_PER_PAGE);
+    let items_page_index = query_param.index.unwrap_or(DEFAULT_PAGE_INDEX);
+
+    if items_page_index < 1 {
+        return Err(AppError::PublishableErr("invalid page index".to_string()));
+    }
+
+    let items_page_index = items_page_index - 1;
+
+    if items_per_page == 0 || items_per_page > MAX_ITEMS_PER_PAGE {
+        return Err(AppError::PublishableErr(format!(
+            "Items per page is limited up to {}.",
+            MAX_ITEMS_PER_PAGE
+        )));
+    }
+
+    let mut query = audit_logs::table.into_boxed();
+
+    if let Some(tgt_action) = &query_param.action {
+        query = query.filter(audit_logs::action.eq(tgt_action));
+    }
+    if let Some(tgt_target_id) = query_param.target_id {
+        query = query.filter(audit_logs::target_id.eq(tgt_target_id));
+    }
+
+    let result = query
+        .order(audit_logs::created_time.desc())
+        .offset((items_per_page * items_page_index).into())
+        .limit(items_per_page.into())
+        .load::<AuditLog>(&conn)?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
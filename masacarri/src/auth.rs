@@ -0,0 +1,271 @@
+use std::future::{ready, Ready};
+use std::str::FromStr;
+
+use crate::db::{MainDbConnection, Pool};
+use crate::error::{AppError, AppResult};
+use crate::models::{CountResult, Role, User};
+use crate::schema::{site_users, sites, users};
+use crate::apitoken::find_token_user;
+use crate::user::is_user_disabled;
+use actix_identity::Identity;
+use actix_web::dev::Payload;
+use actix_web::{http, web, FromRequest, HttpRequest};
+use diesel::{prelude::*, sql_query};
+use diesel::pg::types::sql_types;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Read private pages, held comments, bans and commenters' addresses.
+    ViewPrivate,
+    ModerateComments,
+    ManageBans,
+    ManagePages,
+    ManageSite,
+    ManageUsers,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 6] = [
+        Permission::ViewPrivate,
+        Permission::ModerateComments,
+        Permission::ManageBans,
+        Permission::ManagePages,
+        Permission::ManageSite,
+        Permission::ManageUsers,
+    ];
+
+    /// The name of the permission as an API token scope.
+    pub fn scope(&self) -> &'static str {
+        match self {
+            Permission::ViewPrivate => "private:read",
+            Permission::ModerateComments => "comments:moderate",
+            Permission::ManageBans => "bans:write",
+            Permission::ManagePages => "pages:write",
+            Permission::ManageSite => "sites:write",
+            Permission::ManageUsers => "users:write",
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Permission::ALL
+            .iter()
+            .find(|p| p.scope() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown scope: '{}'", s))
+    }
+}
+
+impl Role {
+    pub fn allows(&self, permission: Permission) -> bool {
+        match self {
+            Role::Owner => true,
+            Role::Admin => permission != Permission::ManageUsers,
+            Role::Moderator => matches!(
+                permission,
+                Permission::ViewPrivate | Permission::ModerateComments | Permission::ManageBans
+            ),
+            Role::ReadOnly => permission == Permission::ViewPrivate,
+        }
+    }
+}
+
+/// The logged-in admin user, loaded from the database on every request.
+pub struct AuthUser {
+    pub id: uuid::Uuid,
+    pub username: String,
+    pub role: Role,
+    /// Scopes of the API token the request was made with. `None` for a login session.
+    pub token_scopes: Option<Vec<Permission>>,
+}
+
+impl AuthUser {
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.role.allows(permission)
+            && self
+                .token_scopes
+                .as_ref()
+                .is_none_or(|scopes| scopes.contains(&permission))
+    }
+
+    pub fn require(&self, permission: Permission) -> AppResult<()> {
+        if !self.allows(permission) {
+            return Err(AppError::ForbiddenErr(
+                "You do not have permission to do this.".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// For account settings such as passwords and tokens, which a token must not change.
+    pub fn require_session(&self) -> AppResult<()> {
+        if self.token_scopes.is_some() {
+            return Err(AppError::ForbiddenErr(
+                "This cannot be done with an API token.".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl FromRequest for AuthUser {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        ready(load_auth_user(req, payload))
+    }
+}
+
+fn load_auth_user(req: &HttpRequest, payload: &mut Payload) -> AppResult<AuthUser> {
+    const AUTH_ERR_MSG: &str = "login required";
+
+    let db = req
+        .app_data::<web::Data<Pool>>()
+        .ok_or(AppError::UnspecifiedErr)?;
+
+    if let Some(authorization) = req.headers().get(http::header::AUTHORIZATION) {
+        let raw_token = authorization
+            .to_str()
+            .ok()
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or_else(|| AppError::AuthErr(AUTH_ERR_MSG.to_string()))?;
+
+        let conn = db.get()?;
+        let (user, scopes) = find_token_user(&conn, raw_token.trim())?
+            .ok_or_else(|| AppError::AuthErr(AUTH_ERR_MSG.to_string()))?;
+
+        return Ok(AuthUser {
+            id: user.id,
+            username: user.username,
+            role: user.role,
+            token_scopes: Some(scopes),
+        });
+    }
+
+    let identity = Identity::from_request(req, payload)
+        .into_inner()
+        .map_err(|_| AppError::AuthErr(AUTH_ERR_MSG.to_string()))?;
+    let r_username = identity
+        .id()
+        .map_err(|_| AppError::AuthErr(AUTH_ERR_MSG.to_string()))?;
+
+    let conn = db.get()?;
+
+    // the account may have been deleted or disabled since the session was issued
+    let user = users::table
+        .filter(users::username.eq(r_username))
+        .first::<User>(&conn)
+        .optional()?
+        .ok_or_else(|| AppError::AuthErr(AUTH_ERR_MSG.to_string()))?;
+    if is_user_disabled(&user) {
+        return Err(AppError::AuthErr(AUTH_ERR_MSG.to_string()));
+    }
+
+    Ok(AuthUser {
+        id: user.id,
+        username: user.username,
+        role: user.role,
+        token_scopes: None,
+    })
+}
+
+/// Sites whose pages the user may administer. Owners administer every site.
+pub fn managed_site_ids(conn: &MainDbConnection, user: &AuthUser) -> AppResult<Vec<uuid::Uuid>> {
+    let result = if user.role == Role::Owner {
+        sites::table.select(sites::id).load::<uuid::Uuid>(conn)?
+    } else {
+        site_users::table
+            .filter(site_users::user_id.eq(user.id))
+            .select(site_users::site_id)
+            .load::<uuid::Uuid>(conn)?
+    };
+
+    Ok(result)
+}
+
+pub fn chk_manage_site(
+    conn: &MainDbConnection,
+    user: &AuthUser,
+    tgt_site_id: uuid::Uuid,
+) -> AppResult<()> {
+    if !managed_site_ids(conn, user)?.contains(&tgt_site_id) {
+        return Err(AppError::NotFoundErr("site not found".to_string()));
+    }
+
+    Ok(())
+}
+
+/// Picks the site a new page or ban goes to. It may be omitted by users of a single site.
+pub fn resolve_site_id(
+    conn: &MainDbConnection,
+    user: &AuthUser,
+    tgt_site_id: Option<uuid::Uuid>,
+) -> AppResult<uuid::Uuid> {
+    let site_ids = managed_site_ids(conn, user)?;
+
+    match (tgt_site_id, site_ids.as_slice()) {
+        (Some(tgt_site_id), _) if site_ids.contains(&tgt_site_id) => Ok(tgt_site_id),
+        (Some(_), _) => Err(AppError::NotFoundErr("site not found".to_string())),
+        (None, [only_site_id]) => Ok(*only_site_id),
+        (None, _) => Err(AppError::PublishableErr("site_id is required.".to_string())),
+    }
+}
+
+fn manages_page(
+    conn: &MainDbConnection,
+    user: &AuthUser,
+    tgt_page_id: uuid::Uuid,
+) -> AppResult<bool> {
+    if user.role == Role::Owner {
+        return Ok(true);
+    }
+
+    let matched = sql_query(
+        r#"
+            select count(*) from pages
+            inner join site_users on site_users.site_id = pages.site_id
+            where pages.id = $1 and site_users.user_id = $2
+        "#,
+    )
+    .bind::<sql_types::Uuid, _>(tgt_page_id)
+    .bind::<sql_types::Uuid, _>(user.id)
+    .get_result::<CountResult>(conn)?
+    .count;
+
+    Ok(matched > 0)
+}
+
+/// Anonymous visitors, admins of other sites and users without `permission` get `false`.
+pub fn can_manage_page(
+    conn: &MainDbConnection,
+    user: &Option<AuthUser>,
+    tgt_page_id: uuid::Uuid,
+    permission: Permission,
+) -> AppResult<bool> {
+    match user {
+        Some(user) if user.allows(permission) => manages_page(conn, user, tgt_page_id),
+        _ => Ok(false),
+    }
+}
+
+/// Pages of other sites are reported as missing rather than forbidden.
+pub fn chk_manage_page(
+    conn: &MainDbConnection,
+    user: &AuthUser,
+    tgt_page_id: uuid::Uuid,
+    permission: Permission,
+) -> AppResult<()> {
+    user.require(permission)?;
+
+    if !manages_page(conn, user, tgt_page_id)? {
+        return Err(AppError::NotFoundErr("page not found".to_string()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,303 @@
+use crate::auth::{chk_manage_page, managed_site_ids, resolve_site_id, AuthUser, Permission};
+use crate::db::{MainDbPooledConnection, Pool};
+use crate::error::{AppError, AppResult};
+use crate::models::{Ban, Comment, CountResult};
+use crate::schema::bans;
+use crate::schema::bans::dsl::*;
+use crate::schema::{comments, pages};
+use crate::utils::empty_to_none;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use diesel::pg::types::sql_types;
+use diesel::sql_types::{Inet, Nullable, Text};
+use diesel::{prelude::*, sql_query};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct NewBanRequest {
+    ip_range: Option<ipnetwork::IpNetwork>,
+    mail_addr: Option<String>,
+    display_name_pattern: Option<String>,
+    reason: Option<String>,
+    expires_time: Option<DateTime<Utc>>,
+    site_id: Option<uuid::Uuid>,
+}
+
+#[derive(Insertable)]
+#[table_name = "bans"]
+pub struct NewBan {
+    id: uuid::Uuid,
+    ip_range: Option<ipnetwork::IpNetwork>,
+    mail_addr: Option<String>,
+    display_name_pattern: Option<String>,
+    reason: Option<String>,
+    created_time: DateTime<Utc>,
+    expires_time: Option<DateTime<Utc>>,
+    site_id: uuid::Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct GetBansRequestQuery {
+    #[serde(default)]
+    active: bool,
+    site: Option<uuid::Uuid>,
+}
+
+#[derive(Deserialize)]
+pub struct ModifyBanRequestPath {
+    ban: uuid::Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteBanRequestPath {
+    ban: uuid::Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct BanAuthorRequestPath {
+    page: uuid::Uuid,
+    comment: uuid::Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct BanAuthorRequest {
+    #[serde(default = "default_true")]
+    by_ip: bool,
+    #[serde(default = "default_true")]
+    by_mail: bool,
+    reason: Option<String>,
+    expires_time: Option<DateTime<Utc>>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// `display_name_pattern` is matched with ILIKE, so `%` and `_` are wildcards.
+pub fn chk_not_banned(
+    conn: &MainDbPooledConnection,
+    tgt_site_id: uuid::Uuid,
+    tgt_ip_addr: ipnetwork::IpNetwork,
+    tgt_mail_addr: Option<&str>,
+    tgt_display_name: &str,
+) -> AppResult<()> {
+    let matched = sql_query(
+        r#"
+            select count(*) from bans
+            where site_id = $4
+            and (expires_time is null or expires_time > now())
+            and (
+                ip_range >>= $1
+                or lower(mail_addr) = lower($2)
+                or $3 ilike display_name_pattern
+            )
+        "#,
+    )
+    .bind::<Inet, _>(tgt_ip_addr)
+    .bind::<Nullable<Text>, _>(tgt_mail_addr)
+    .bind::<Text, _>(tgt_display_name)
+    .bind::<sql_types::Uuid, _>(tgt_site_id)
+    .get_result::<CountResult>(conn)?
+    .count;
+
+    if matched > 0 {
+        return Err(AppError::ForbiddenErr(
+            "You are not allowed to post comments.".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn insert_ban(conn: &MainDbPooledConnection, new_ban: NewBan) -> AppResult<Ban> {
+    if new_ban.ip_range.is_none()
+        && new_ban.mail_addr.is_none()
+        && new_ban.display_name_pattern.is_none()
+    {
+        return Err(AppError::PublishableErr(
+            "A ban needs an IP range, a mail address or a display name pattern.".to_string(),
+        ));
+    }
+
+    let result = diesel::insert_into(bans)
+        .values(new_ban)
+        .get_result::<Ban>(conn)?;
+
+    Ok(result)
+}
+
+pub async fn get_bans(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    query_param: web::Query<GetBansRequestQuery>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    user.require(Permission::ViewPrivate)?;
+
+    let mut site_ids = managed_site_ids(&conn, &user)?;
+    if let Some(tgt_site_id) = query_param.site {
+        site_ids.retain(|v| *v == tgt_site_id);
+    }
+
+    let result = if query_param.active {
+        bans.filter(site_id.eq_any(site_ids))
+            .filter(expires_time.is_null().or(expires_time.gt(Utc::now())))
+            .order(created_time.desc())
+            .load::<Ban>(&conn)?
+    } else {
+        bans.filter(site_id.eq_any(site_ids))
+            .order(created_time.desc())
+            .load::<Ban>(&conn)?
+    };
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+pub async fn add_ban(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    new_ban: web::Json<NewBanRequest>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    let NewBanRequest {
+        ip_range: r_ip_range,
+        mail_addr: r_mail_addr,
+        display_name_pattern: r_display_name_pattern,
+        reason: r_reason,
+        expires_time: r_expires_time,
+        site_id: r_site_id,
+    } = new_ban.into_inner();
+
+    user.require(Permission::ManageBans)?;
+    let r_site_id = resolve_site_id(&conn, &user, r_site_id)?;
+
+    let result = insert_ban(
+        &conn,
+        NewBan {
+            id: uuid::Uuid::new_v4(),
+            ip_range: r_ip_range,
+            mail_addr: empty_to_none(r_mail_addr),
+            display_name_pattern: empty_to_none(r_display_name_pattern),
+            reason: empty_to_none(r_reason),
+            created_time: Utc::now(),
+            expires_time: r_expires_time,
+            site_id: r_site_id,
+        },
+    )?;
+
+    Ok(HttpResponse::Created().json(result))
+}
+
+pub async fn modify_ban(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    path_param: web::Path<ModifyBanRequestPath>,
+    updated_ban: web::Json<NewBanRequest>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    let NewBanRequest {
+        ip_range: r_ip_range,
+        mail_addr: r_mail_addr,
+        display_name_pattern: r_display_name_pattern,
+        reason: r_reason,
+        expires_time: r_expires_time,
+        site_id: _,
+    } = updated_ban.into_inner();
+
+    let r_mail_addr = empty_to_none(r_mail_addr);
+    let r_display_name_pattern = empty_to_none(r_display_name_pattern);
+
+    if r_ip_range.is_none() && r_mail_addr.is_none() && r_display_name_pattern.is_none() {
+        return Err(AppError::PublishableErr(
+            "A ban needs an IP range, a mail address or a display name pattern.".to_string(),
+        ));
+    }
+
+    user.require(Permission::ManageBans)?;
+    let site_ids = managed_site_ids(&conn, &user)?;
+
+    let updated = diesel::update(
+        bans.filter(id.eq(path_param.ban))
+            .filter(site_id.eq_any(site_ids)),
+    )
+    .set((
+        ip_range.eq(r_ip_range),
+        mail_addr.eq(r_mail_addr),
+        display_name_pattern.eq(r_display_name_pattern),
+        reason.eq(empty_to_none(r_reason)),
+        expires_time.eq(r_expires_time),
+    ))
+    .execute(&conn)?;
+    if updated == 0 {
+        return Err(AppError::NotFoundErr("ban not found".to_string()));
+    }
+    Ok(HttpResponse::NoContent())
+}
+
+pub async fn delete_ban(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    path_param: web::Path<DeleteBanRequestPath>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    user.require(Permission::ManageBans)?;
+    let site_ids = managed_site_ids(&conn, &user)?;
+
+    let deleted = diesel::delete(
+        bans.filter(id.eq(path_param.ban))
+            .filter(site_id.eq_any(site_ids)),
+    )
+    .execute(&conn)?;
+    if deleted == 0 {
+        return Err(AppError::NotFoundErr("ban not found".to_string()));
+    }
+    Ok(HttpResponse::NoContent())
+}
+
+pub async fn ban_comment_author(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    path_param: web::Path<BanAuthorRequestPath>,
+    ban_request: web::Json<BanAuthorRequest>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    chk_manage_page(&conn, &user, path_param.page, Permission::ManageBans)?;
+
+    let comment = comments::table
+        .filter(comments::page_id.eq(path_param.page))
+        .filter(comments::id.eq(path_param.comment))
+        .first::<Comment>(&conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFoundErr("comment not found".to_string()))?;
+
+    let BanAuthorRequest {
+        by_ip,
+        by_mail,
+        reason: r_reason,
+        expires_time: r_expires_time,
+    } = ban_request.into_inner();
+
+    let result = insert_ban(
+        &conn,
+        NewBan {
+            id: uuid::Uuid::new_v4(),
+            ip_range: if by_ip { Some(comment.ip_addr) } else { None },
+            mail_addr: if by_mail { comment.mail_addr } else { None },
+            display_name_pattern: None,
+            reason: empty_to_none(r_reason),
+            created_time: Utc::now(),
+            expires_time: r_expires_time,
+            site_id: pages::table
+                .select(pages::site_id)
+                .filter(pages::id.eq(path_param.page))
+                .first(&conn)?,
+        },
+    )?;
+
+    Ok(HttpResponse::Created().json(result))
+}
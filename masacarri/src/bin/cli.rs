@@ -1,15 +1,24 @@
 use std::env;
 use std::io::stdin;
 
+use diesel::Connection;
 use diesel::ExpressionMethods;
 use diesel::Insertable;
 use diesel::QueryDsl;
 use diesel::RunQueryDsl;
 use dotenv::dotenv;
 
-use masacarri::models::Page;
+use masacarri::apitoken::{create_token, API_TOKEN_COLUMNS};
+use masacarri::auth::Permission;
+use masacarri::models::{ApiToken, Page, Role, Site, DEFAULT_SITE_ID};
+use masacarri::schema::api_tokens;
 use masacarri::schema::pages;
+use masacarri::schema::site_users;
+use masacarri::schema::sites;
 use masacarri::schema::users;
+use masacarri::site::NewSite;
+use masacarri::totp::reset_totp;
+use masacarri::user::hash_password;
 use masacarri::{
     db::{self, *},
     models::User,
@@ -22,23 +31,81 @@ struct NewUser {
     username: String,
     password_hash: String,
     flags: i32,
+    role: Role,
 }
 
-fn add_user(conn: MainDbConnection, username: &str) {
+#[derive(Insertable)]
+#[table_name = "site_users"]
+struct NewSiteUser {
+    site_id: uuid::Uuid,
+    user_id: uuid::Uuid,
+}
+
+/// Accepts a site name or id.
+fn find_site(conn: &MainDbConnection, site: &str) -> Option<Site> {
+    let by_name = sites::dsl::sites
+        .filter(sites::dsl::name.eq(site))
+        .first::<Site>(conn);
+    if let Ok(found) = by_name {
+        return Some(found);
+    }
+
+    let site_id = uuid::Uuid::parse_str(site).ok()?;
+    sites::dsl::sites.find(site_id).first::<Site>(conn).ok()
+}
+
+fn find_user_id(conn: &MainDbConnection, username: &str) -> Option<uuid::Uuid> {
+    users::dsl::users
+        .filter(users::dsl::username.eq(username))
+        .select(users::dsl::id)
+        .first::<uuid::Uuid>(conn)
+        .ok()
+}
+
+fn add_user(conn: MainDbConnection, username: &str, site: Option<&str>) {
     let new_id = uuid::Uuid::new_v4();
 
-    let res = diesel::insert_into(users::dsl::users)
-        .values(NewUser {
-            id: new_id,
-            username: username.to_string(),
-            password_hash: "-".to_string(),
-            flags: 0,
-        })
-        .execute(&conn);
+    let site_id = match site {
+        Some(site) => match find_site(&conn, site) {
+            Some(found) => found.id,
+            None => {
+                eprintln!("site not found: {}", site);
+                return;
+            }
+        },
+        None => DEFAULT_SITE_ID,
+    };
+
+    let res = conn.transaction::<_, diesel::result::Error, _>(|| {
+        // the first user sets everything else up, so it starts as an owner
+        let num_users: i64 = users::dsl::users.count().get_result(&conn)?;
+        let role = if num_users == 0 {
+            Role::Owner
+        } else {
+            Role::ReadOnly
+        };
+
+        diesel::insert_into(users::dsl::users)
+            .values(NewUser {
+                id: new_id,
+                username: username.to_string(),
+                password_hash: "-".to_string(),
+                flags: 0,
+                role,
+            })
+            .execute(&conn)?;
+        diesel::insert_into(site_users::dsl::site_users)
+            .values(NewSiteUser {
+                site_id,
+                user_id: new_id,
+            })
+            .execute(&conn)?;
+        Ok(role)
+    });
 
     match res {
-        Ok(_) => {
-            println!("user creation succeeded: {}", username);
+        Ok(role) => {
+            println!("user creation succeeded: {} ({})", username, role.as_str());
         }
         Err(_) => {
             eprintln!("failed to create user");
@@ -66,7 +133,7 @@ fn list_users(conn: MainDbConnection) {
     match res {
         Ok(user_list) => {
             for user in user_list {
-                println!("{} (id:{})", user.username, user.id);
+                println!("{} (id:{}, role:{})", user.username, user.id, user.role.as_str());
             }
         }
         Err(_) => {
@@ -90,6 +157,244 @@ fn list_pages(conn: MainDbConnection) {
     };
 }
 
+fn list_sites(conn: MainDbConnection) {
+    let res = sites::dsl::sites.load::<Site>(&conn);
+
+    match res {
+        Ok(site_list) => {
+            for site in site_list {
+                println!("{} (id:{})", site.name, site.id);
+            }
+        }
+        Err(_) => {
+            eprintln!("failed to load sites");
+        }
+    };
+}
+
+fn list_tokens(conn: MainDbConnection) {
+    let res = api_tokens::dsl::api_tokens
+        .inner_join(users::dsl::users)
+        .select((API_TOKEN_COLUMNS, users::dsl::username))
+        .load::<(ApiToken, String)>(&conn);
+
+    match res {
+        Ok(token_list) => {
+            for (token, username) in token_list {
+                println!(
+                    "{} (id:{}, user:{}, scopes:{}, expires:{})",
+                    token.name,
+                    token.id,
+                    username,
+                    if token.scopes.is_empty() {
+                        "all".to_string()
+                    } else {
+                        token.scopes.join(",")
+                    },
+                    token
+                        .expires_time
+                        .map_or_else(|| "never".to_string(), |t| t.to_rfc3339())
+                );
+            }
+        }
+        Err(_) => {
+            eprintln!("failed to load tokens");
+        }
+    };
+}
+
+fn add_token(
+    conn: MainDbConnection,
+    username: &str,
+    name: &str,
+    scopes: Option<&str>,
+    expires_in_days: Option<&str>,
+) {
+    let user_id = match find_user_id(&conn, username) {
+        Some(user_id) => user_id,
+        None => {
+            eprintln!("user not found");
+            return;
+        }
+    };
+
+    let scopes = match scopes
+        .unwrap_or("")
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<Permission>())
+        .collect::<Result<Vec<Permission>, String>>()
+    {
+        Ok(scopes) => scopes,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    let expires_time = match expires_in_days.map(|days| days.parse::<i64>()) {
+        Some(Ok(days)) => Some(chrono::Utc::now() + chrono::Duration::days(days)),
+        Some(Err(_)) => {
+            eprintln!("days must be a number");
+            return;
+        }
+        None => None,
+    };
+
+    match create_token(&conn, user_id, name, &scopes, expires_time) {
+        Ok((token, secret)) => {
+            println!("Token creation succeeded: {} (id:{})", token.name, token.id);
+            println!("{}", secret);
+        }
+        Err(_) => {
+            eprintln!("failed to create token");
+        }
+    };
+}
+
+fn delete_token(conn: MainDbConnection, token_id: &str) {
+    let token_id = match token_id.parse::<uuid::Uuid>() {
+        Ok(token_id) => token_id,
+        Err(_) => {
+            eprintln!("token id is invalid");
+            return;
+        }
+    };
+
+    let res = diesel::delete(api_tokens::dsl::api_tokens.find(token_id)).execute(&conn);
+
+    match res {
+        Ok(1) => {
+            println!("Token deletion succeeded");
+        }
+        Ok(_) => {
+            eprintln!("token not found");
+        }
+        Err(_) => {
+            eprintln!("failed to delete token");
+        }
+    };
+}
+
+fn add_site(conn: MainDbConnection, name: &str) {
+    let res = diesel::insert_into(sites::dsl::sites)
+        .values(NewSite {
+            id: uuid::Uuid::new_v4(),
+            name: name.to_string(),
+            allowed_origins: vec![],
+            mail_from: None,
+            premoderated: false,
+        })
+        .execute(&conn);
+
+    match res {
+        Ok(_) => {
+            println!("site creation succeeded: {}", name);
+        }
+        Err(_) => {
+            eprintln!("failed to create site");
+        }
+    };
+}
+
+fn join_site(conn: MainDbConnection, username: &str, site: &str) {
+    let (user_id, site) = match (find_user_id(&conn, username), find_site(&conn, site)) {
+        (Some(user_id), Some(site)) => (user_id, site),
+        (None, _) => {
+            eprintln!("user not found");
+            return;
+        }
+        (_, None) => {
+            eprintln!("site not found");
+            return;
+        }
+    };
+
+    let res = diesel::insert_into(site_users::dsl::site_users)
+        .values(NewSiteUser {
+            site_id: site.id,
+            user_id,
+        })
+        .on_conflict_do_nothing()
+        .execute(&conn);
+
+    match res {
+        Ok(_) => {
+            println!("{} can now manage {}", username, site.name);
+        }
+        Err(_) => {
+            eprintln!("failed to add user to site");
+        }
+    };
+}
+
+fn leave_site(conn: MainDbConnection, username: &str, site: &str) {
+    let (user_id, site) = match (find_user_id(&conn, username), find_site(&conn, site)) {
+        (Some(user_id), Some(site)) => (user_id, site),
+        (None, _) => {
+            eprintln!("user not found");
+            return;
+        }
+        (_, None) => {
+            eprintln!("site not found");
+            return;
+        }
+    };
+
+    let res = diesel::delete(
+        site_users::dsl::site_users
+            .filter(site_users::dsl::site_id.eq(site.id))
+            .filter(site_users::dsl::user_id.eq(user_id)),
+    )
+    .execute(&conn);
+
+    match res {
+        Ok(_) => {
+            println!("{} no longer manages {}", username, site.name);
+        }
+        Err(_) => {
+            eprintln!("failed to remove user from site");
+        }
+    };
+}
+
+fn set_role(conn: MainDbConnection, username: &str, role: Role) {
+    let res = diesel::update(users::dsl::users.filter(users::dsl::username.eq(username)))
+        .set(users::dsl::role.eq(role))
+        .execute(&conn);
+
+    match res {
+        Ok(1) => {
+            println!("{} is now {}", username, role.as_str());
+        }
+        Ok(_) => {
+            eprintln!("user not found");
+        }
+        Err(_) => {
+            eprintln!("failed to update role");
+        }
+    };
+}
+
+fn reset_two_factor(conn: MainDbConnection, username: &str) {
+    let user_id = match find_user_id(&conn, username) {
+        Some(user_id) => user_id,
+        None => {
+            eprintln!("user not found");
+            return;
+        }
+    };
+
+    match reset_totp(&conn, user_id) {
+        Ok(_) => {
+            println!("two-factor authentication of {} is reset", username);
+        }
+        Err(_) => {
+            eprintln!("failed to reset two-factor authentication");
+        }
+    };
+}
+
 fn update_password(conn: MainDbConnection, username: &str) {
     let user_count = users::dsl::users
         .filter(users::dsl::username.eq(username))
@@ -131,13 +436,12 @@ fn update_password(conn: MainDbConnection, username: &str) {
         return;
     }
 
-    let hash = bcrypt::hash(passwd, 8);
-
-    let hash = if let Ok(hash) = hash {
-        hash
-    } else {
-        eprintln!("failed to update password");
-        return;
+    let hash = match hash_password(&passwd) {
+        Ok(hash) => hash,
+        Err(e) => {
+            eprintln!("failed to update password: {}", e);
+            return;
+        }
     };
 
     let res = diesel::update(users::dsl::users.filter(users::dsl::username.eq(username)))
@@ -161,18 +465,20 @@ fn main() {
     let conn = db::establish_main_db();
 
     if args.len() <= 1 {
-        eprintln!("command needed: adduser, deluser, list, passwd");
+        eprintln!(
+            "command needed: adduser, deluser, list, passwd, reset2fa, grant, revoke, addsite, joinsite, leavesite, addtoken, deltoken"
+        );
         return;
     }
 
     match args[1].as_str() {
         "adduser" => {
             if args.len() <= 2 {
-                eprintln!("username needed: masacarri_cli adduser [username]");
+                eprintln!("username needed: masacarri_cli adduser [username] [site(optional)]");
                 return;
             }
 
-            add_user(conn, args[2].as_str());
+            add_user(conn, args[2].as_str(), args.get(3).map(|s| s.as_str()));
         }
         "deluser" => {
             if args.len() <= 2 {
@@ -184,12 +490,14 @@ fn main() {
         }
         "list" => {
             if args.len() <= 2 {
-                eprintln!("paramater needed: masacarri_cli list [user,page]");
+                eprintln!("paramater needed: masacarri_cli list [user,page,site,token]");
                 return;
             }
             match args[2].as_str() {
                 "user" => list_users(conn),
                 "page" => list_pages(conn),
+                "site" => list_sites(conn),
+                "token" => list_tokens(conn),
                 item => eprintln!("unknown list parameter: '{}'", item),
             }
         }
@@ -200,6 +508,75 @@ fn main() {
             }
             update_password(conn, args[2].as_str());
         }
+        "reset2fa" => {
+            if args.len() <= 2 {
+                eprintln!("username needed: masacarri_cli reset2fa [username]");
+                return;
+            }
+            reset_two_factor(conn, args[2].as_str());
+        }
+        "grant" => {
+            if args.len() <= 3 {
+                eprintln!(
+                    "username and role needed: masacarri_cli grant [username] [owner,admin,moderator,readonly]"
+                );
+                return;
+            }
+            match args[3].parse::<Role>() {
+                Ok(role) => set_role(conn, args[2].as_str(), role),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
+        "revoke" => {
+            if args.len() <= 2 {
+                eprintln!("username needed: masacarri_cli revoke [username]");
+                return;
+            }
+            set_role(conn, args[2].as_str(), Role::ReadOnly);
+        }
+        "addsite" => {
+            if args.len() <= 2 {
+                eprintln!("site name needed: masacarri_cli addsite [name]");
+                return;
+            }
+            add_site(conn, args[2].as_str());
+        }
+        "joinsite" => {
+            if args.len() <= 3 {
+                eprintln!("username and site needed: masacarri_cli joinsite [username] [site]");
+                return;
+            }
+            join_site(conn, args[2].as_str(), args[3].as_str());
+        }
+        "leavesite" => {
+            if args.len() <= 3 {
+                eprintln!("username and site needed: masacarri_cli leavesite [username] [site]");
+                return;
+            }
+            leave_site(conn, args[2].as_str(), args[3].as_str());
+        }
+        "addtoken" => {
+            if args.len() <= 3 {
+                eprintln!(
+                    "username and token name needed: masacarri_cli addtoken [username] [name] [scopes(optional, comma separated)] [days until expiry(optional)]"
+                );
+                return;
+            }
+            add_token(
+                conn,
+                args[2].as_str(),
+                args[3].as_str(),
+                args.get(4).map(|s| s.as_str()),
+                args.get(5).map(|s| s.as_str()),
+            );
+        }
+        "deltoken" => {
+            if args.len() <= 2 {
+                eprintln!("token id needed: masacarri_cli deltoken [id]");
+                return;
+            }
+            delete_token(conn, args[2].as_str());
+        }
         cmd => {
             eprintln!("unknown command: '{}'", cmd);
         }
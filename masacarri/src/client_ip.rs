@@ -0,0 +1,129 @@
+use std::env;
+use std::future::{ready, Ready};
+use std::net::{IpAddr, SocketAddr};
+
+use crate::error::{AppError, AppResult};
+use actix_web::dev::Payload;
+use actix_web::http::header::HeaderName;
+use actix_web::{web, FromRequest, HttpRequest};
+use ipnetwork::IpNetwork;
+
+/// Reverse proxies whose forwarding headers are believed.
+#[derive(Clone, Default)]
+pub struct TrustedProxies(Vec<IpNetwork>);
+
+impl TrustedProxies {
+    pub fn from_env() -> Self {
+        let networks = env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                s.parse::<IpNetwork>()
+                    .unwrap_or_else(|_| panic!("TRUSTED_PROXIES has an invalid entry: {}", s))
+            })
+            .collect();
+        TrustedProxies(networks)
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(ip))
+    }
+}
+
+/// The address of the client as seen through trusted proxies.
+pub struct ClientIp(pub IpAddr);
+
+impl ClientIp {
+    pub fn network(&self) -> IpNetwork {
+        IpNetwork::from(self.0)
+    }
+}
+
+impl FromRequest for ClientIp {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(resolve_client_ip(req).map(ClientIp))
+    }
+}
+
+/// Accepts `1.2.3.4`, `1.2.3.4:80`, `[::1]`, `[::1]:80` and `::1`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .and_then(|s| s.parse::<IpAddr>().ok())
+}
+
+fn header_values<'a>(req: &'a HttpRequest, name: &'static str) -> Vec<&'a str> {
+    req.headers()
+        .get_all(HeaderName::from_static(name))
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect()
+}
+
+/// Hops from the original client to the nearest proxy. `None` marks a hop
+/// that could not be parsed (e.g. `for=unknown`).
+fn forwarded_chain(req: &HttpRequest) -> Vec<Option<IpAddr>> {
+    let forwarded = header_values(req, "forwarded");
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    if key.trim().eq_ignore_ascii_case("for") {
+                        Some(parse_node(value))
+                    } else {
+                        None
+                    }
+                })
+                .flatten()
+            })
+            .collect();
+    }
+
+    let forwarded_for = header_values(req, "x-forwarded-for");
+    if !forwarded_for.is_empty() {
+        return forwarded_for.into_iter().map(parse_node).collect();
+    }
+
+    header_values(req, "x-real-ip")
+        .into_iter()
+        .take(1)
+        .map(parse_node)
+        .collect()
+}
+
+fn resolve_client_ip(req: &HttpRequest) -> AppResult<IpAddr> {
+    let peer = req.peer_addr().ok_or(AppError::UnspecifiedErr)?.ip();
+
+    let proxies = match req.app_data::<web::Data<TrustedProxies>>() {
+        Some(proxies) => proxies,
+        None => return Ok(peer),
+    };
+
+    // walk back from the nearest hop and stop at the first one we do not operate
+    let mut client = peer;
+    for hop in forwarded_chain(req).into_iter().rev() {
+        if !proxies.is_trusted(client) {
+            break;
+        }
+        match hop {
+            Some(ip) => client = ip,
+            None => break,
+        }
+    }
+
+    Ok(client)
+}
@@ -1,22 +1,39 @@
+use crate::audit;
+use crate::auth::{can_manage_page, chk_manage_page, AuthUser, Permission};
+use crate::ban::chk_not_banned;
 use crate::bgtask::BgTaskManager;
+use crate::client_ip::ClientIp;
+use crate::commenter::AuthCommenter;
 use crate::db::{MainDbPooledConnection, Pool};
 use crate::error::{AppError, AppResult};
 use crate::mail::MailNotifyTask;
-use crate::models::{Comment, CommentWithReplies, CountResult};
+use crate::markdown::MarkdownRenderer;
+use crate::models::{
+    Comment, CommentRevision, CommentStatus, CommentWithReplies, CountResult, IdResult, User,
+};
+use crate::moderation::is_premoderation_enabled;
+use crate::page::chk_move_target;
+use crate::ratelimit::RateLimiter;
 use crate::schema::comments::dsl::*;
-use crate::schema::{self, comments};
+use crate::schema::{self, comment_revisions, comments, spam_reports};
+use crate::spamfilter::{SpamCheckTarget, SpamFilterPipeline, SpamVerdict};
+use crate::user::{author_name, is_name_reserved};
 use crate::utils::empty_to_none;
-use actix_identity::Identity;
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web::{web, HttpResponse, Responder};
 use chrono::{DateTime, Utc};
 use diesel::pg::types::sql_types;
-use diesel::sql_types::BigInt;
+use diesel::sql_types::{BigInt, Bool, Integer, Text};
+use diesel::dsl::sql;
 use diesel::{prelude::*, sql_query};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use static_assertions::const_assert;
+use std::collections::{BTreeMap, HashSet};
 
-const MARK_AS_SPAM_FRAG_BIT: i32 = 1;
+pub const MARK_AS_SPAM_FRAG_BIT: i32 = 1;
+pub const MARK_AS_DELETED_FRAG_BIT: i32 = 2;
+pub const MARK_AS_PINNED_FRAG_BIT: i32 = 4;
+pub const MARK_AS_LOCKED_FRAG_BIT: i32 = 8;
 
 const DEFAULT_COMMENTS_PER_PAGE: u32 = 10;
 const DEFAULT_PAGE_INDEX: u32 = 1;
@@ -33,6 +50,8 @@ pub struct NewCommentRequest {
     mail_addr: Option<String>,
     content: String,
     delete_key: Option<String>,
+    #[serde(default)]
+    honeypot: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -54,6 +73,21 @@ pub struct NewComment {
     delete_key: String,
     flags: i32,
     created_time: DateTime<Utc>,
+    status: CommentStatus,
+    content_html: String,
+    commenter_id: Option<uuid::Uuid>,
+    avatar_url: Option<String>,
+    author_user_id: Option<uuid::Uuid>,
+}
+
+#[derive(Insertable)]
+#[table_name = "spam_reports"]
+pub struct NewSpamReport {
+    id: uuid::Uuid,
+    comment_id: uuid::Uuid,
+    filter_name: String,
+    score: f64,
+    reason: String,
 }
 
 #[derive(Deserialize)]
@@ -61,12 +95,37 @@ pub struct GetCommentsRequestPath {
     page: uuid::Uuid,
 }
 
+/// Pinned comments come first in every order.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommentSort {
+    #[default]
+    Oldest,
+    Newest,
+    MostReplied,
+    /// By the total number of reactions.
+    Top,
+}
+
+impl CommentSort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommentSort::Oldest => "oldest",
+            CommentSort::Newest => "newest",
+            CommentSort::MostReplied => "most_replied",
+            CommentSort::Top => "top",
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct GetCommentsRequestQuery {
     num: Option<u32>,
     index: Option<u32>,
     replyto: Option<uuid::Uuid>,
     contextof: Option<uuid::Uuid>,
+    #[serde(default)]
+    sort: CommentSort,
 }
 
 #[derive(Deserialize)]
@@ -77,15 +136,30 @@ pub struct GetCommentRequestPath {
 
 #[derive(Queryable, Serialize)]
 pub struct GetCommentResponse {
-    id: uuid::Uuid,
-    page_id: uuid::Uuid,
-    reply_to: Option<uuid::Uuid>,
-    display_name: String,
-    site_url: Option<String>,
-    content: String,
-    count_replies: Option<i64>,
-    created_time: DateTime<Utc>,
-    is_spam: Option<bool>,
+    pub id: uuid::Uuid,
+    pub page_id: uuid::Uuid,
+    pub reply_to: Option<uuid::Uuid>,
+    pub display_name: String,
+    pub site_url: Option<String>,
+    pub content: String,
+    pub content_html: String,
+    pub count_replies: Option<i64>,
+    pub created_time: DateTime<Utc>,
+    pub status: CommentStatus,
+    pub is_spam: Option<bool>,
+    pub is_deleted: Option<bool>,
+    pub is_pinned: Option<bool>,
+    /// No replies are taken below this comment.
+    pub is_locked: Option<bool>,
+    /// Posted by a commenter signed in through the OpenID Connect provider.
+    pub verified: bool,
+    pub avatar_url: Option<String>,
+    /// Posted by an admin user of the site, under their display name.
+    pub author: bool,
+    /// The number of reactions of each kind.
+    pub reactions: Option<BTreeMap<String, i64>>,
+    pub edited: bool,
+    pub edited_time: Option<DateTime<Utc>>,
 }
 
 #[derive(Deserialize)]
@@ -94,9 +168,174 @@ pub struct MarkCommentRequestPath {
     comment: uuid::Uuid,
 }
 
+/// Marks that are left out stay as they are.
 #[derive(Deserialize)]
 pub struct MarkCommentRequest {
-    spam: bool,
+    spam: Option<bool>,
+    pinned: Option<bool>,
+    locked: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteCommentRequestPath {
+    page: uuid::Uuid,
+    comment: uuid::Uuid,
+}
+
+/// `Purge` removes the comment and every reply below it from the database.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeleteMode {
+    #[default]
+    Tombstone,
+    Purge,
+}
+
+/// Moderators of the page may leave out the delete key.
+#[derive(Deserialize)]
+pub struct DeleteCommentRequest {
+    delete_key: Option<String>,
+    #[serde(default)]
+    mode: DeleteMode,
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EditCommentRequestPath {
+    page: uuid::Uuid,
+    comment: uuid::Uuid,
+}
+
+/// Moderators of the page may leave out the delete key.
+#[derive(Deserialize)]
+pub struct EditCommentRequest {
+    delete_key: Option<String>,
+    content: String,
+}
+
+#[derive(Deserialize)]
+pub struct CommentRevisionsRequestPath {
+    page: uuid::Uuid,
+    comment: uuid::Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct RestoreCommentRevisionRequestPath {
+    page: uuid::Uuid,
+    comment: uuid::Uuid,
+    revision: uuid::Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct MoveCommentsRequestPath {
+    page: uuid::Uuid,
+}
+
+/// Without `comment` every comment of the page is moved.
+#[derive(Deserialize)]
+pub struct MoveCommentsRequest {
+    to_page: uuid::Uuid,
+    comment: Option<uuid::Uuid>,
+}
+
+#[derive(Insertable)]
+#[table_name = "comment_revisions"]
+pub struct NewCommentRevision {
+    id: uuid::Uuid,
+    comment_id: uuid::Uuid,
+    content: String,
+    content_html: String,
+    created_time: DateTime<Utc>,
+    replaced_time: DateTime<Utc>,
+    replaced_by_user_id: Option<uuid::Uuid>,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn make_comment_response(
+    r_id: uuid::Uuid,
+    r_page_id: uuid::Uuid,
+    r_reply_to: Option<uuid::Uuid>,
+    r_display_name: String,
+    r_site_url: Option<String>,
+    r_content: String,
+    r_content_html: String,
+    r_flags: i32,
+    r_count_replies: Option<i64>,
+    r_created_time: DateTime<Utc>,
+    r_status: CommentStatus,
+    r_commenter_id: Option<uuid::Uuid>,
+    r_avatar_url: Option<String>,
+    r_author_user_id: Option<uuid::Uuid>,
+    r_reactions: Option<serde_json::Value>,
+    r_edited_time: Option<DateTime<Utc>>,
+) -> GetCommentResponse {
+    let is_spam = (r_flags & MARK_AS_SPAM_FRAG_BIT) == MARK_AS_SPAM_FRAG_BIT;
+    let is_deleted = (r_flags & MARK_AS_DELETED_FRAG_BIT) == MARK_AS_DELETED_FRAG_BIT;
+    let is_pinned = (r_flags & MARK_AS_PINNED_FRAG_BIT) == MARK_AS_PINNED_FRAG_BIT;
+    let is_locked = (r_flags & MARK_AS_LOCKED_FRAG_BIT) == MARK_AS_LOCKED_FRAG_BIT;
+
+    let (
+        r_display_name,
+        r_site_url,
+        r_content,
+        r_content_html,
+        r_commenter_id,
+        r_avatar_url,
+        r_author_user_id,
+    ) = if is_deleted {
+        (
+            "(deleted)".to_string(),
+            None,
+            "(This comment has been deleted.)".to_string(),
+            "<p>(This comment has been deleted.)</p>".to_string(),
+            None,
+            None,
+            None,
+        )
+    } else if is_spam {
+        (
+            "(spam user)".to_string(),
+            None,
+            "(This comment is marked as spam.)".to_string(),
+            "<p>(This comment is marked as spam.)</p>".to_string(),
+            None,
+            None,
+            None,
+        )
+    } else {
+        (
+            r_display_name,
+            r_site_url,
+            r_content,
+            r_content_html,
+            r_commenter_id,
+            r_avatar_url,
+            r_author_user_id,
+        )
+    };
+
+    GetCommentResponse {
+        id: r_id,
+        page_id: r_page_id,
+        reply_to: r_reply_to,
+        display_name: r_display_name,
+        site_url: r_site_url,
+        content: r_content,
+        content_html: r_content_html,
+        is_spam: if is_spam { Some(true) } else { None },
+        is_deleted: if is_deleted { Some(true) } else { None },
+        is_pinned: if is_pinned { Some(true) } else { None },
+        is_locked: if is_locked { Some(true) } else { None },
+        count_replies: r_count_replies,
+        created_time: r_created_time,
+        status: r_status,
+        verified: r_commenter_id.is_some(),
+        avatar_url: r_avatar_url,
+        author: r_author_user_id.is_some(),
+        reactions: r_reactions.map(|v| serde_json::from_value(v).unwrap_or_default()),
+        edited: r_edited_time.is_some(),
+        edited_time: r_edited_time,
+    }
 }
 
 impl From<CommentWithReplies> for GetCommentResponse {
@@ -114,21 +353,33 @@ impl From<CommentWithReplies> for GetCommentResponse {
             flags: r_flags,
             count_replies: r_count_replies,
             created_time: r_created_time,
+            status: r_status,
+            content_html: r_content_html,
+            commenter_id: r_commenter_id,
+            avatar_url: r_avatar_url,
+            author_user_id: r_author_user_id,
+            edited_time: r_edited_time,
+            reactions: r_reactions,
         } = comment;
 
-        let is_spam = (r_flags & MARK_AS_SPAM_FRAG_BIT) == MARK_AS_SPAM_FRAG_BIT;
-
-        GetCommentResponse {
-            id: r_id,
-            page_id: r_page_id,
-            reply_to: r_reply_to,
-            display_name: if is_spam { "(spam user)".to_string() } else { r_display_name },
-            site_url: if is_spam { None } else { r_site_url },
-            content: if is_spam { "(This comment is marked as spam.)".to_string() } else { r_content },
-            is_spam: if is_spam { Some(true) } else { None },
-            count_replies: Some(r_count_replies),
-            created_time: r_created_time,
-        }
+        make_comment_response(
+            r_id,
+            r_page_id,
+            r_reply_to,
+            r_display_name,
+            r_site_url,
+            r_content,
+            r_content_html,
+            r_flags,
+            Some(r_count_replies),
+            r_created_time,
+            r_status,
+            r_commenter_id,
+            r_avatar_url,
+            r_author_user_id,
+            Some(r_reactions),
+            r_edited_time,
+        )
     }
 }
 
@@ -146,25 +397,36 @@ impl From<Comment> for GetCommentResponse {
             delete_key: _,
             flags: r_flags,
             created_time: r_created_time,
+            status: r_status,
+            content_html: r_content_html,
+            commenter_id: r_commenter_id,
+            avatar_url: r_avatar_url,
+            author_user_id: r_author_user_id,
+            edited_time: r_edited_time,
         } = comment;
 
-        let is_spam = (r_flags & MARK_AS_SPAM_FRAG_BIT) == MARK_AS_SPAM_FRAG_BIT;
-
-        GetCommentResponse {
-            id: r_id,
-            page_id: r_page_id,
-            reply_to: r_reply_to,
-            display_name: if is_spam { "(spam user)".to_string() } else { r_display_name },
-            site_url: if is_spam { None } else { r_site_url },
-            content: if is_spam { "(This comment is marked as spam.)".to_string() } else { r_content },
-            is_spam: if is_spam { Some(true) } else { None },
-            count_replies: None,
-            created_time: r_created_time,
-        }
+        make_comment_response(
+            r_id,
+            r_page_id,
+            r_reply_to,
+            r_display_name,
+            r_site_url,
+            r_content,
+            r_content_html,
+            r_flags,
+            None,
+            r_created_time,
+            r_status,
+            r_commenter_id,
+            r_avatar_url,
+            r_author_user_id,
+            None,
+            r_edited_time,
+        )
     }
 }
 
-fn chk_page_public(conn: &MainDbPooledConnection, tgt_page_id: uuid::Uuid) -> AppResult<()> {
+pub fn chk_page_public(conn: &MainDbPooledConnection, tgt_page_id: uuid::Uuid) -> AppResult<()> {
     let is_public: bool = schema::pages::dsl::pages
         .select(schema::pages::dsl::published)
         .filter(schema::pages::dsl::id.eq(tgt_page_id))
@@ -179,18 +441,126 @@ fn chk_page_public(conn: &MainDbPooledConnection, tgt_page_id: uuid::Uuid) -> Ap
     Ok(())
 }
 
-pub async fn add_comment(
-    db: web::Data<Pool>,
-    path_param: web::Path<NewCommentRequestPath>,
-    req: HttpRequest,
-    new_comment: web::Json<NewCommentRequest>,
-    bgtask_manager: web::Data<BgTaskManager>,
-    user: Option<Identity>,
-) -> AppResult<impl Responder> {
+/// Archived pages take no changes to their comments.
+fn chk_page_not_archived(conn: &MainDbPooledConnection, tgt_page_id: uuid::Uuid) -> AppResult<()> {
+    let is_archived: bool = schema::pages::table
+        .select(schema::pages::archived)
+        .filter(schema::pages::id.eq(tgt_page_id))
+        .first(conn)?;
+
+    if is_archived {
+        return Err(AppError::PublishableErr(
+            "This page is archived.".to_owned(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Deleted comments are treated as missing.
+fn find_live_comment(
+    conn: &MainDbPooledConnection,
+    tgt_page_id: uuid::Uuid,
+    tgt_comment_id: uuid::Uuid,
+) -> AppResult<Comment> {
+    let comment = comments
+        .filter(page_id.eq(tgt_page_id))
+        .filter(id.eq(tgt_comment_id))
+        .first::<Comment>(conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFoundErr("comment not found".to_string()))?;
+
+    if (comment.flags & MARK_AS_DELETED_FRAG_BIT) == MARK_AS_DELETED_FRAG_BIT {
+        return Err(AppError::NotFoundErr("comment not found".to_string()));
+    }
+
+    Ok(comment)
+}
+
+fn chk_delete_key(
+    conn: &MainDbPooledConnection,
+    tgt_page_id: uuid::Uuid,
+    tgt_comment_id: uuid::Uuid,
+    key: &str,
+) -> AppResult<Comment> {
+    let comment = find_live_comment(conn, tgt_page_id, tgt_comment_id)?;
+
+    if let Ok(true) = bcrypt::verify(key, &comment.delete_key) {
+        Ok(comment)
+    } else {
+        Err(AppError::ForbiddenErr("The delete key is incorrect.".to_string()))
+    }
+}
+
+/// Whether the comment or any comment above it is locked.
+fn is_thread_locked(conn: &MainDbPooledConnection, tgt_comment_id: uuid::Uuid) -> AppResult<bool> {
+    let locked = sql_query(
+        r#"
+            with recursive tree as (
+                select comments.reply_to, comments.flags
+                from comments
+                where comments.id = $1
+                union all
+                    select comments.reply_to, comments.flags
+                    from tree, comments
+                    where tree.reply_to = comments.id
+            )
+            select count(*) from tree where flags & $2 <> 0
+        "#,
+    )
+    .bind::<sql_types::Uuid, _>(tgt_comment_id)
+    .bind::<Integer, _>(MARK_AS_LOCKED_FRAG_BIT)
+    .get_result::<CountResult>(conn)?
+    .count;
+
+    Ok(locked > 0)
+}
+
+/// The comments of the page which are locked or below a locked one.
+pub fn locked_comment_ids(
+    conn: &MainDbPooledConnection,
+    tgt_page_id: uuid::Uuid,
+) -> AppResult<HashSet<uuid::Uuid>> {
+    let locked = sql_query(
+        r#"
+            with recursive tree as (
+                select comments.id
+                from comments
+                where comments.page_id = $1 and comments.flags & $2 <> 0
+                union
+                    select comments.id
+                    from tree, comments
+                    where comments.reply_to = tree.id
+            )
+            select id from tree
+        "#,
+    )
+    .bind::<sql_types::Uuid, _>(tgt_page_id)
+    .bind::<Integer, _>(MARK_AS_LOCKED_FRAG_BIT)
+    .load::<IdResult>(conn)?;
+
+    Ok(locked.into_iter().map(|r| r.id).collect())
+}
+
+/// Validates and stores a new comment. Shared by the JSON API and the embed form.
+#[allow(clippy::too_many_arguments)]
+pub async fn post_comment(
+    db: &Pool,
+    tgt_page_id: uuid::Uuid,
+    ipaddr: ipnetwork::IpNetwork,
+    new_comment: NewCommentRequest,
+    bgtask_manager: &BgTaskManager,
+    spam_filter: &SpamFilterPipeline,
+    rate_limiter: &RateLimiter,
+    markdown: &MarkdownRenderer,
+    user: &Option<AuthUser>,
+    commenter: &Option<AuthCommenter>,
+) -> AppResult<Comment> {
     let conn = db.get()?;
 
-    if user.is_none() {
-        chk_page_public(&conn, path_param.page)?;
+    let is_manager = can_manage_page(&conn, user, tgt_page_id, Permission::ModerateComments)?;
+    if !is_manager {
+        chk_page_public(&conn, tgt_page_id)?;
     }
 
     let NewCommentRequest {
@@ -200,40 +570,58 @@ pub async fn add_comment(
         mail_addr: r_mail_addr,
         content: r_content,
         delete_key: r_delete_key,
-    } = new_comment.into_inner();
+        honeypot: r_honeypot,
+    } = new_comment;
+
+    let r_delete_key = empty_to_none(r_delete_key);
 
     if let Some(reply_to_id) = r_reply_to {
-        let reply_to_page_id = comments
-            .select(page_id)
+        let (reply_to_page_id, reply_to_status) = comments
+            .select((page_id, status))
             .filter(id.eq(reply_to_id))
-            .first::<uuid::Uuid>(&conn)?;
+            .first::<(uuid::Uuid, CommentStatus)>(&conn)?;
 
-        if reply_to_page_id != path_param.page {
+        if reply_to_page_id != tgt_page_id
+            || (!is_manager && reply_to_status != CommentStatus::Approved)
+        {
             return Err(AppError::PublishableErr(
                 "You replied to an invalid comment.".to_string(),
             ));
         }
+
+        if !is_manager && is_thread_locked(&conn, reply_to_id)? {
+            return Err(AppError::PublishableErr(
+                "This thread is locked.".to_string(),
+            ));
+        }
     }
 
     let new_id = uuid::Uuid::new_v4();
 
-    let ipaddr = req.peer_addr().ok_or(AppError::UnspecifiedErr)?.ip();
-
-    let ipaddr = ipnetwork::IpNetwork::new(
-        ipaddr,
-        if ipaddr.is_ipv4() {
-            32
-        } else if ipaddr.is_ipv6() {
-            128
-        } else {
-            0
-        },
-    )?;
-
-    let r_delete_key = match r_delete_key {
-        Some(val) => bcrypt::hash(val, 16).unwrap(),
-        None => "-".to_string(),
+    // admin users of the site post as the author, and signed-in commenters under the name given
+    // by the provider. Others may not take the name of an author.
+    let author = match user {
+        Some(u) if can_manage_page(&conn, user, tgt_page_id, Permission::ViewPrivate)? => {
+            Some(schema::users::table.find(u.id).first::<User>(&conn)?)
+        }
+        _ => None,
+    };
+    let (r_display_name, r_commenter_id, r_avatar_url, r_author_user_id) = match (&author, commenter)
+    {
+        (Some(a), _) => (author_name(a), None, None, Some(a.id)),
+        (None, Some(c)) => (
+            c.commenter.display_name.clone(),
+            Some(c.commenter.id),
+            c.commenter.avatar_url.clone(),
+            None,
+        ),
+        (None, None) => (r_display_name, None, None, None),
     };
+    if author.is_none() && is_name_reserved(&conn, tgt_page_id, &r_display_name)? {
+        return Err(AppError::PublishableErr(
+            "This name is reserved. Please use another one.".to_owned(),
+        ));
+    }
 
     if r_display_name.is_empty() {
         return Err(AppError::PublishableErr(
@@ -246,75 +634,195 @@ pub async fn add_comment(
         ));
     }
 
-    let res = diesel::insert_into(comments)
-        .values(NewComment {
-            id: new_id,
-            page_id: path_param.page,
-            reply_to: r_reply_to,
-            ip_addr: ipaddr,
-            display_name: r_display_name,
-            site_url: empty_to_none(r_site_url),
-            mail_addr: empty_to_none(r_mail_addr),
-            content: r_content,
-            delete_key: r_delete_key,
-            flags: 0,
-            created_time: Utc::now(),
-        })
-        .execute(&conn);
-    if let Err(_) = res {
+    let r_site_url = empty_to_none(r_site_url);
+    let r_mail_addr = empty_to_none(r_mail_addr);
+
+    let (
+        tgt_site_id,
+        is_page_premoderated,
+        is_site_premoderated,
+        is_anonymous_allowed,
+        is_page_archived,
+    ) = schema::pages::table
+        .inner_join(schema::sites::table)
+        .select((
+            schema::sites::id,
+            schema::pages::premoderated,
+            schema::sites::premoderated,
+            schema::pages::allow_anonymous,
+            schema::pages::archived,
+        ))
+        .filter(schema::pages::id.eq(tgt_page_id))
+        .first::<(uuid::Uuid, bool, bool, bool, bool)>(&conn)?;
+
+    if is_page_archived {
         return Err(AppError::PublishableErr(
-            "Failed to post new comment.".to_owned(),
+            "This page is archived.".to_owned(),
         ));
     }
 
+    if !is_manager && !is_anonymous_allowed && commenter.is_none() {
+        return Err(AppError::ForbiddenErr(
+            "Sign in to comment on this page.".to_owned(),
+        ));
+    }
+
+    if !is_manager {
+        chk_not_banned(&conn, tgt_site_id, ipaddr, r_mail_addr.as_deref(), &r_display_name)?;
+    }
+
+    // only posts which could be stored count against the limits
+    if !is_manager {
+        rate_limiter.check_comment(ipaddr.ip(), tgt_page_id).await?;
+    }
+
+    let r_delete_key = match r_delete_key {
+        Some(val) => bcrypt::hash(val, 16).unwrap(),
+        None => "-".to_string(),
+    };
+
+    let spam_report = if !is_manager {
+        let report = spam_filter.check(
+            &conn,
+            &SpamCheckTarget {
+                ip_addr: ipaddr,
+                display_name: &r_display_name,
+                site_url: r_site_url.as_deref(),
+                mail_addr: r_mail_addr.as_deref(),
+                content: &r_content,
+                honeypot: r_honeypot.as_deref(),
+            },
+        )?;
+        if report.verdict == SpamVerdict::Reject {
+            return Err(AppError::PublishableErr(
+                "Your comment was rejected as spam.".to_owned(),
+            ));
+        }
+        Some(report)
+    } else {
+        None
+    };
+
+    let is_held = match &spam_report {
+        Some(report) => report.verdict == SpamVerdict::Hold,
+        None => false,
+    };
+
+    let r_status = if !is_manager
+        && (is_held || is_page_premoderated || is_site_premoderated || is_premoderation_enabled())
+    {
+        CommentStatus::Pending
+    } else {
+        CommentStatus::Approved
+    };
+
+    conn.transaction(|| {
+        let res = diesel::insert_into(comments)
+            .values(NewComment {
+                id: new_id,
+                page_id: tgt_page_id,
+                reply_to: r_reply_to,
+                ip_addr: ipaddr,
+                display_name: r_display_name,
+                site_url: r_site_url,
+                mail_addr: r_mail_addr,
+                content_html: markdown.render(&r_content),
+                content: r_content,
+                delete_key: r_delete_key,
+                flags: 0,
+                created_time: Utc::now(),
+                status: r_status,
+                commenter_id: r_commenter_id,
+                avatar_url: r_avatar_url,
+                author_user_id: r_author_user_id,
+            })
+            .execute(&conn);
+        if let Err(_) = res {
+            return Err(AppError::PublishableErr(
+                "Failed to post new comment.".to_owned(),
+            ));
+        }
+
+        if let Some(report) = spam_report {
+            let new_reports: Vec<NewSpamReport> = report
+                .reasons
+                .into_iter()
+                .map(|r| NewSpamReport {
+                    id: uuid::Uuid::new_v4(),
+                    comment_id: new_id,
+                    filter_name: r.filter_name,
+                    score: r.score,
+                    reason: r.reason,
+                })
+                .collect();
+            diesel::insert_into(spam_reports::table)
+                .values(&new_reports)
+                .execute(&conn)?;
+        }
+
+        Ok::<_, AppError>(())
+    })?;
+
     let mut result = comments.filter(id.eq(new_id)).load::<Comment>(&conn)?;
     if result.len() != 1 {
         return Err(AppError::UnspecifiedErr);
     }
 
     let comment_new = result.pop().ok_or(AppError::UnspecifiedErr)?;
-    if let Some(id_replyto) = comment_new.reply_to {
-        let comment_new = comment_new.clone();
-
+    if let (Some(id_replyto), CommentStatus::Approved) = (comment_new.reply_to, comment_new.status) {
         bgtask_manager.do_send(MailNotifyTask {
             id_replyto: id_replyto,
-            conn: conn,
-            comment_new: comment_new,
+            pool: db.clone(),
+            comment_new: comment_new.clone(),
         });
     }
 
-    Ok(HttpResponse::Created().json(GetCommentResponse::from(comment_new)))
+    Ok(comment_new)
 }
 
-pub async fn get_comments(
+#[allow(clippy::too_many_arguments)]
+pub async fn add_comment(
     db: web::Data<Pool>,
-    path_param: web::Path<GetCommentsRequestPath>,
-    query_param: web::Query<GetCommentsRequestQuery>,
-    user: Option<Identity>,
+    path_param: web::Path<NewCommentRequestPath>,
+    client_ip: ClientIp,
+    new_comment: web::Json<NewCommentRequest>,
+    bgtask_manager: web::Data<BgTaskManager>,
+    spam_filter: web::Data<SpamFilterPipeline>,
+    rate_limiter: web::Data<RateLimiter>,
+    markdown: web::Data<MarkdownRenderer>,
+    user: Option<AuthUser>,
+    commenter: Option<AuthCommenter>,
 ) -> AppResult<impl Responder> {
-    let conn = db.get()?;
-
-    if user.is_none() {
-        chk_page_public(&conn, path_param.page)?;
-    }
-
-    let comments_per_page = query_param.num.unwrap_or(DEFAULT_COMMENTS_PER_PAGE);
-    let comments_page_index = query_param.index.unwrap_or(DEFAULT_PAGE_INDEX);
-
-    if comments_page_index < 1 {
-        return Err(AppError::PublishableErr("invalid page index".to_string()));
-    }
-
-    let comments_page_index = comments_page_index - 1;
+    let comment_new = post_comment(
+        &db,
+        path_param.page,
+        client_ip.network(),
+        new_comment.into_inner(),
+        &bgtask_manager,
+        &spam_filter,
+        &rate_limiter,
+        &markdown,
+        &user,
+        &commenter,
+    )
+    .await?;
 
-    if 0 >= comments_per_page || comments_per_page > MAX_COMMENTS_PER_PAGE {
-        return Err(AppError::PublishableErr(format!(
-            "Comments per page is limited up to {}.",
-            MAX_COMMENTS_PER_PAGE
-        )));
-    }
+    Ok(HttpResponse::Created().json(GetCommentResponse::from(comment_new)))
+}
 
-    let result = match (query_param.replyto, query_param.contextof) {
+/// `comments_page_index` starts from 0 here.
+#[allow(clippy::too_many_arguments)]
+pub fn load_comments(
+    conn: &MainDbPooledConnection,
+    tgt_page_id: uuid::Uuid,
+    replyto: Option<uuid::Uuid>,
+    contextof: Option<uuid::Uuid>,
+    comments_per_page: u32,
+    comments_page_index: u32,
+    show_all: bool,
+    sort: CommentSort,
+) -> AppResult<Vec<CommentWithReplies>> {
+    let result = match (replyto, contextof) {
         (Some(_), Some(_)) => {
             return Err(AppError::PublishableErr(format!(
                 "'replyto' and 'contextof' are not allowed to use simultaneously.",
@@ -322,13 +830,29 @@ pub async fn get_comments(
         },
         (Some(reply_to_id), None) => sql_query(
             r#"
-                select comments.*, count(child_comments.id) as count_replies
+                select comments.*, replies.count_replies, reaction_totals.reactions
                 from comments
-                left join comments as child_comments 
-                on comments.id = child_comments.reply_to
-                where comments.reply_to = $1
-                group by comments.id
-                order by created_time
+                cross join lateral (
+                    select count(*) as count_replies
+                    from comments as child_comments
+                    where child_comments.reply_to = comments.id
+                    and ($4 or child_comments.status = 'approved')
+                ) as replies
+                cross join lateral (
+                    select coalesce(jsonb_object_agg(kind, n), '{}'::jsonb) as reactions, coalesce(sum(n), 0) as total_reactions
+                    from (
+                        select kind, count(*) as n from reactions
+                        where reactions.comment_id = comments.id
+                        group by kind
+                    ) as counted
+                ) as reaction_totals
+                where comments.reply_to = $1 and comments.page_id = $7
+                and ($4 or comments.status = 'approved')
+                order by (comments.flags & $5) <> 0 desc,
+                case when $6 = 'most_replied' then replies.count_replies end desc,
+                case when $6 = 'top' then reaction_totals.total_reactions end desc,
+                case when $6 = 'newest' then comments.created_time end desc,
+                comments.created_time, comments.id
                 offset $2
                 limit $3;
             "#,
@@ -336,26 +860,44 @@ pub async fn get_comments(
         .bind::<sql_types::Uuid, _>(reply_to_id)
         .bind::<BigInt, i64>((comments_per_page * comments_page_index).into())
         .bind::<BigInt, i64>((comments_per_page).into())
-        .load::<CommentWithReplies>(&conn)?,
+        .bind::<Bool, _>(show_all)
+        .bind::<Integer, _>(MARK_AS_PINNED_FRAG_BIT)
+        .bind::<Text, _>(sort.as_str())
+        .bind::<sql_types::Uuid, _>(tgt_page_id)
+        .load::<CommentWithReplies>(conn)?,
         (None, Some(target_comment_id)) => sql_query(
             r#"
             with recursive tree as (
                 select comments.*
                 from comments
-                where comments.id = $1
+                where comments.id = $1 and comments.page_id = $7
                 union all
                     select comments.*
                     from tree, comments
-                    where tree.reply_to = comments.id
+                    where tree.reply_to = comments.id and comments.page_id = $7
             )
-            select * from (
-                select distinct on (tree.id) tree.*, count(comments.id) over (partition by tree.id) as count_replies
-                from tree
-                left join comments
-                on tree.id = comments.reply_to
-                order by tree.id
-            ) as context
-            order by created_time
+            select tree.*, replies.count_replies, reaction_totals.reactions
+            from tree
+            cross join lateral (
+                select count(*) as count_replies
+                from comments as child_comments
+                where child_comments.reply_to = tree.id
+                and ($4 or child_comments.status = 'approved')
+            ) as replies
+            cross join lateral (
+                select coalesce(jsonb_object_agg(kind, n), '{}'::jsonb) as reactions, coalesce(sum(n), 0) as total_reactions
+                from (
+                    select kind, count(*) as n from reactions
+                    where reactions.comment_id = tree.id
+                    group by kind
+                ) as counted
+            ) as reaction_totals
+            where $4 or tree.status = 'approved'
+            order by (tree.flags & $5) <> 0 desc,
+            case when $6 = 'most_replied' then replies.count_replies end desc,
+            case when $6 = 'top' then reaction_totals.total_reactions end desc,
+            case when $6 = 'newest' then tree.created_time end desc,
+            tree.created_time, tree.id
             offset $2
             limit $3;
             "#,
@@ -363,26 +905,152 @@ pub async fn get_comments(
         .bind::<sql_types::Uuid, _>(target_comment_id)
         .bind::<BigInt, i64>((comments_per_page * comments_page_index).into())
         .bind::<BigInt, i64>((comments_per_page).into())
-        .load::<CommentWithReplies>(&conn)?,
+        .bind::<Bool, _>(show_all)
+        .bind::<Integer, _>(MARK_AS_PINNED_FRAG_BIT)
+        .bind::<Text, _>(sort.as_str())
+        .bind::<sql_types::Uuid, _>(tgt_page_id)
+        .load::<CommentWithReplies>(conn)?,
         (None, None) => sql_query(
             r#"
-                select comments.*, count(child_comments.id) as count_replies
+                select comments.*, replies.count_replies, reaction_totals.reactions
                 from comments
-                left join comments as child_comments
-                on comments.id = child_comments.reply_to
+                cross join lateral (
+                    select count(*) as count_replies
+                    from comments as child_comments
+                    where child_comments.reply_to = comments.id
+                    and ($4 or child_comments.status = 'approved')
+                ) as replies
+                cross join lateral (
+                    select coalesce(jsonb_object_agg(kind, n), '{}'::jsonb) as reactions, coalesce(sum(n), 0) as total_reactions
+                    from (
+                        select kind, count(*) as n from reactions
+                        where reactions.comment_id = comments.id
+                        group by kind
+                    ) as counted
+                ) as reaction_totals
                 where comments.page_id = $1
-                group by comments.id
-                order by created_time
+                and ($4 or comments.status = 'approved')
+                order by (comments.flags & $5) <> 0 desc,
+                case when $6 = 'most_replied' then replies.count_replies end desc,
+                case when $6 = 'top' then reaction_totals.total_reactions end desc,
+                case when $6 = 'newest' then comments.created_time end desc,
+                comments.created_time, comments.id
                 offset $2
                 limit $3;
             "#,
         )
-        .bind::<sql_types::Uuid, _>(path_param.page)
+        .bind::<sql_types::Uuid, _>(tgt_page_id)
         .bind::<BigInt, i64>((comments_per_page * comments_page_index).into())
         .bind::<BigInt, i64>((comments_per_page).into())
-        .load::<CommentWithReplies>(&conn)?,
+        .bind::<Bool, _>(show_all)
+        .bind::<Integer, _>(MARK_AS_PINNED_FRAG_BIT)
+        .bind::<Text, _>(sort.as_str())
+        .load::<CommentWithReplies>(conn)?,
     };
 
+    Ok(result)
+}
+
+pub fn count_comments(
+    conn: &MainDbPooledConnection,
+    tgt_page_id: uuid::Uuid,
+    replyto: Option<uuid::Uuid>,
+    contextof: Option<uuid::Uuid>,
+    show_all: bool,
+) -> AppResult<i64> {
+    let result: i64 = match (replyto, contextof) {
+        (None, None) if show_all => comments
+            .filter(page_id.eq(tgt_page_id))
+            .count()
+            .get_result(conn)?,
+        (None, None) => comments
+            .filter(page_id.eq(tgt_page_id))
+            .filter(status.eq(CommentStatus::Approved))
+            .count()
+            .get_result(conn)?,
+        (None, Some(contextof_id)) => {
+            sql_query(
+                r#"
+            with recursive tree as (
+                select comments.reply_to, comments.status
+                from comments
+                where comments.id = $1 and comments.page_id = $2
+                union all
+                    select comments.reply_to, comments.status
+                    from tree, comments
+                    where tree.reply_to = comments.id and comments.page_id = $2
+            )
+            select count(*) from tree
+            where $3 or tree.status = 'approved'
+            "#,
+            )
+            .bind::<sql_types::Uuid, _>(contextof_id)
+            .bind::<sql_types::Uuid, _>(tgt_page_id)
+            .bind::<Bool, _>(show_all)
+            .get_result::<CountResult>(conn)?
+            .count
+        }
+        (Some(reply_to_id), None) if show_all => comments
+            .filter(reply_to.eq(reply_to_id))
+            .filter(page_id.eq(tgt_page_id))
+            .count()
+            .get_result(conn)?,
+        (Some(reply_to_id), None) => comments
+            .filter(reply_to.eq(reply_to_id))
+            .filter(page_id.eq(tgt_page_id))
+            .filter(status.eq(CommentStatus::Approved))
+            .count()
+            .get_result(conn)?,
+        (Some(_), Some(_)) => {
+            return Err(AppError::PublishableErr(format!(
+                "'replyto' and 'contextof' are not allowed to use simultaneously.",
+            )));
+        }
+    };
+
+    Ok(result)
+}
+
+pub async fn get_comments(
+    db: web::Data<Pool>,
+    path_param: web::Path<GetCommentsRequestPath>,
+    query_param: web::Query<GetCommentsRequestQuery>,
+    user: Option<AuthUser>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    let show_all = can_manage_page(&conn, &user, path_param.page, Permission::ViewPrivate)?;
+    if !show_all {
+        chk_page_public(&conn, path_param.page)?;
+    }
+
+    let comments_per_page = query_param.num.unwrap_or(DEFAULT_COMMENTS_PER_PAGE);
+    let comments_page_index = query_param.index.unwrap_or(DEFAULT_PAGE_INDEX);
+
+    if comments_page_index < 1 {
+        return Err(AppError::PublishableErr("invalid page index".to_string()));
+    }
+
+    let comments_page_index = comments_page_index - 1;
+
+    if 0 >= comments_per_page || comments_per_page > MAX_COMMENTS_PER_PAGE {
+        return Err(AppError::PublishableErr(format!(
+            "Comments per page is limited up to {}.",
+            MAX_COMMENTS_PER_PAGE
+        )));
+    }
+
+    let result = load_comments(
+        &conn,
+        path_param.page,
+        query_param.replyto,
+        query_param.contextof,
+        comments_per_page,
+        comments_page_index,
+        show_all,
+        query_param.sort,
+    )?;
+
     let showing_comments: Vec<_> = result
         .into_iter()
         .map(move |comment| GetCommentResponse::from(comment))
@@ -394,26 +1062,40 @@ pub async fn get_comments(
 pub async fn get_comment(
     db: web::Data<Pool>,
     path_param: web::Path<GetCommentRequestPath>,
-    user: Option<Identity>,
+    user: Option<AuthUser>,
 ) -> AppResult<impl Responder> {
     let conn = db.get()?;
 
-    if user.is_none() {
+    let show_all = can_manage_page(&conn, &user, path_param.page, Permission::ViewPrivate)?;
+    if !show_all {
         chk_page_public(&conn, path_param.page)?;
     }
 
     let result = sql_query(
         r#"
-                select comments.*, count(child_comments.id) as count_replies
+                select comments.*, replies.count_replies, reaction_totals.reactions
                 from comments
-                left join comments as child_comments
-                on comments.id = child_comments.reply_to
+                cross join lateral (
+                    select count(*) as count_replies
+                    from comments as child_comments
+                    where child_comments.reply_to = comments.id
+                    and ($3 or child_comments.status = 'approved')
+                ) as replies
+                cross join lateral (
+                    select coalesce(jsonb_object_agg(kind, n), '{}'::jsonb) as reactions, coalesce(sum(n), 0) as total_reactions
+                    from (
+                        select kind, count(*) as n from reactions
+                        where reactions.comment_id = comments.id
+                        group by kind
+                    ) as counted
+                ) as reaction_totals
                 where comments.id = $1 and comments.page_id = $2
-                group by comments.id
+                and ($3 or comments.status = 'approved')
             "#,
     )
     .bind::<sql_types::Uuid, _>(path_param.comment)
     .bind::<sql_types::Uuid, _>(path_param.page)
+    .bind::<Bool, _>(show_all)
     .load::<CommentWithReplies>(&conn)?;
 
     let result = result.into_iter().next();
@@ -430,77 +1112,461 @@ pub async fn mark_comment(
     db: web::Data<Pool>,
     path_param: web::Path<MarkCommentRequestPath>,
     mark: web::Json<MarkCommentRequest>,
-    _: Identity,
+    spam_filter: web::Data<SpamFilterPipeline>,
+    user: AuthUser,
 ) -> AppResult<impl Responder> {
     let conn = db.get()?;
 
-    let flags_old: i32 = comments
-        .select(flags)
-        .filter(page_id.eq(path_param.page))
-        .filter(id.eq(path_param.comment))
-        .first(&conn)?;
+    chk_manage_page(&conn, &user, path_param.page, Permission::ModerateComments)?;
 
-    let flags_reset_mask = !(MARK_AS_SPAM_FRAG_BIT);
-    let flags_set_mask = if mark.spam {
-        MARK_AS_SPAM_FRAG_BIT
-    } else {
-        0
-    };
+    let mut flags_reset_mask = !0;
+    let mut flags_set_mask = 0;
+    for (mark_value, mark_bit) in [
+        (mark.spam, MARK_AS_SPAM_FRAG_BIT),
+        (mark.pinned, MARK_AS_PINNED_FRAG_BIT),
+        (mark.locked, MARK_AS_LOCKED_FRAG_BIT),
+    ] {
+        match mark_value {
+            Some(true) => flags_set_mask |= mark_bit,
+            Some(false) => flags_reset_mask &= !mark_bit,
+            None => {}
+        }
+    }
 
-    diesel::update(comments.find(path_param.comment))
-        .set(flags.eq(flags_old & flags_reset_mask | flags_set_mask))
-        .get_result::<Comment>(&conn)?;
+    // one statement, so that marks set at the same time by others are kept
+    let updated = diesel::update(
+        comments
+            .filter(page_id.eq(path_param.page))
+            .filter(id.eq(path_param.comment)),
+    )
+    .set(flags.eq(sql::<Integer>(&format!(
+        "(flags & {}) | {}",
+        flags_reset_mask, flags_set_mask
+    ))))
+    .execute(&conn)?;
+    if updated == 0 {
+        return Err(AppError::NotFoundErr("comment not found".to_string()));
+    }
+
+    if mark.spam.is_some() {
+        spam_filter.invalidate();
+    }
 
     Ok(HttpResponse::NoContent())
 }
 
-pub async fn get_comment_count(
-    db: web::Data<Pool>,
-    path_param: web::Path<GetCommentsRequestPath>,
-    query_param: web::Query<GetCommentsRequestQuery>,
-    user: Option<Identity>,
-) -> AppResult<impl Responder> {
-    let conn = db.get()?;
+/// Blanks the comment but keeps the row, so that replies to it stay reachable.
+fn tombstone_comment(conn: &MainDbPooledConnection, comment: &Comment) -> AppResult<()> {
+    diesel::delete(comment_revisions::table.filter(comment_revisions::comment_id.eq(comment.id)))
+        .execute(conn)?;
+    diesel::update(comments.find(comment.id))
+        .set((
+            display_name.eq(""),
+            site_url.eq(None::<String>),
+            mail_addr.eq(None::<String>),
+            content.eq(""),
+            content_html.eq(""),
+            delete_key.eq("-"),
+            flags.eq(comment.flags | MARK_AS_DELETED_FRAG_BIT),
+        ))
+        .execute(conn)?;
 
-    if user.is_none() {
-        chk_page_public(&conn, path_param.page)?;
-    }
+    Ok(())
+}
 
-    let result: i64 = match (query_param.replyto, query_param.contextof) {
-        (None, None) => comments
-            .filter(page_id.eq(path_param.page))
-            .count()
-            .get_result(&conn)?,
-        (None, Some(contextof_id)) => {
-            sql_query(
-                r#"
+/// Deletes the comment and all replies below it. Returns how many comments were deleted.
+fn purge_comment_tree(
+    conn: &MainDbPooledConnection,
+    tgt_page_id: uuid::Uuid,
+    tgt_comment_id: uuid::Uuid,
+) -> AppResult<usize> {
+    // one statement, so the reply_to foreign key is only checked once the whole tree is gone
+    let deleted = sql_query(
+        r#"
             with recursive tree as (
-                select comments.reply_to
+                select comments.id
                 from comments
-                where comments.id = $1
+                where comments.id = $1 and comments.page_id = $2
                 union all
-                    select comments.reply_to
+                    select comments.id
                     from tree, comments
-                    where tree.reply_to = comments.id
+                    where comments.reply_to = tree.id
             )
-            select count(*) from tree
+            delete from comments
+            where comments.id in (select tree.id from tree)
+        "#,
+    )
+    .bind::<sql_types::Uuid, _>(tgt_comment_id)
+    .bind::<sql_types::Uuid, _>(tgt_page_id)
+    .execute(conn)?;
+
+    Ok(deleted)
+}
+
+pub async fn delete_comment(
+    db: web::Data<Pool>,
+    path_param: web::Path<DeleteCommentRequestPath>,
+    delete_request: web::Json<DeleteCommentRequest>,
+    user: Option<AuthUser>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    let DeleteCommentRequest {
+        delete_key: r_delete_key,
+        mode: r_mode,
+        reason: r_reason,
+    } = delete_request.into_inner();
+
+    if let Some(key) = r_delete_key {
+        if r_mode != DeleteMode::Tombstone {
+            return Err(AppError::ForbiddenErr(
+                "Only moderators can purge comments.".to_string(),
+            ));
+        }
+        let comment = chk_delete_key(&conn, path_param.page, path_param.comment, &key)?;
+        tombstone_comment(&conn, &comment)?;
+        return Ok(HttpResponse::NoContent());
+    }
+
+    let user = user.ok_or_else(|| {
+        AppError::ForbiddenErr("The delete key is required.".to_string())
+    })?;
+
+    match r_mode {
+        DeleteMode::Tombstone => {
+            chk_manage_page(&conn, &user, path_param.page, Permission::ModerateComments)?;
+            let comment = find_live_comment(&conn, path_param.page, path_param.comment)?;
+            conn.transaction(|| {
+                tombstone_comment(&conn, &comment)?;
+                audit::record(
+                    &conn,
+                    &user,
+                    "comment.tombstone",
+                    comment.id,
+                    json!({
+                        "page_id": path_param.page,
+                        "reason": r_reason,
+                    }),
+                )
+            })?;
+        }
+        DeleteMode::Purge => {
+            chk_manage_page(&conn, &user, path_param.page, Permission::ManagePages)?;
+            conn.transaction(|| {
+                let deleted = purge_comment_tree(&conn, path_param.page, path_param.comment)?;
+                if deleted == 0 {
+                    return Err(AppError::NotFoundErr("comment not found".to_string()));
+                }
+                audit::record(
+                    &conn,
+                    &user,
+                    "comment.purge",
+                    path_param.comment,
+                    json!({
+                        "page_id": path_param.page,
+                        "deleted_comments": deleted,
+                        "reason": r_reason,
+                    }),
+                )
+            })?;
+        }
+    }
+
+    Ok(HttpResponse::NoContent())
+}
+
+/// Moves a thread, or with `root` left out every comment, to another page.
+/// Only whole threads move, so replies stay on the page of the comment they reply to.
+/// Returns how many comments were moved.
+pub fn move_comments(
+    conn: &MainDbPooledConnection,
+    from_page_id: uuid::Uuid,
+    to_page_id: uuid::Uuid,
+    root: Option<uuid::Uuid>,
+) -> AppResult<usize> {
+    if from_page_id == to_page_id {
+        return Err(AppError::PublishableErr(
+            "Choose another page to move the comments to.".to_string(),
+        ));
+    }
+
+    conn.transaction(|| {
+        let moved = match root {
+            Some(root_id) => {
+                let root_reply_to = comments
+                    .select(reply_to)
+                    .filter(id.eq(root_id))
+                    .filter(page_id.eq(from_page_id))
+                    .first::<Option<uuid::Uuid>>(conn)
+                    .optional()?
+                    .ok_or_else(|| AppError::NotFoundErr("comment not found".to_string()))?;
+                if root_reply_to.is_some() {
+                    return Err(AppError::PublishableErr(
+                        "Only whole threads can be moved. Move the comment this one replies to."
+                            .to_string(),
+                    ));
+                }
+
+                sql_query(
+                    r#"
+                        with recursive tree as (
+                            select comments.id
+                            from comments
+                            where comments.id = $1 and comments.page_id = $2
+                            union all
+                                select comments.id
+                                from tree, comments
+                                where comments.reply_to = tree.id
+                        )
+                        update comments set page_id = $3
+                        where comments.id in (select tree.id from tree)
+                    "#,
+                )
+                .bind::<sql_types::Uuid, _>(root_id)
+                .bind::<sql_types::Uuid, _>(from_page_id)
+                .bind::<sql_types::Uuid, _>(to_page_id)
+                .execute(conn)?
+            }
+            None => diesel::update(comments.filter(page_id.eq(from_page_id)))
+                .set(page_id.eq(to_page_id))
+                .execute(conn)?,
+        };
+
+        let split = sql_query(
+            r#"
+                select count(*) from comments
+                inner join comments as parent
+                on comments.reply_to = parent.id
+                where comments.page_id <> parent.page_id
+                and (comments.page_id in ($1, $2) or parent.page_id in ($1, $2))
             "#,
-            )
-            .bind::<sql_types::Uuid, _>(contextof_id)
-            .get_result::<CountResult>(&conn)?
-            .count
+        )
+        .bind::<sql_types::Uuid, _>(from_page_id)
+        .bind::<sql_types::Uuid, _>(to_page_id)
+        .get_result::<CountResult>(conn)?
+        .count;
+        if split > 0 {
+            return Err(AppError::PublishableErr(
+                "The move would split a thread across pages.".to_string(),
+            ));
         }
-        (Some(reply_to_id), None) => comments
-            .filter(reply_to.eq(reply_to_id))
-            .count()
-            .get_result(&conn)?,
-        (Some(_), Some(_)) => {
-            return Err(AppError::PublishableErr(format!(
-                "'replyto' and 'contextof' are not allowed to use simultaneously.",
-            )));
+
+        Ok(moved)
+    })
+}
+
+pub async fn move_page_comments(
+    db: web::Data<Pool>,
+    path_param: web::Path<MoveCommentsRequestPath>,
+    move_request: web::Json<MoveCommentsRequest>,
+    user: AuthUser,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    let MoveCommentsRequest {
+        to_page: r_to_page,
+        comment: r_comment,
+    } = move_request.into_inner();
+
+    chk_manage_page(&conn, &user, path_param.page, Permission::ManagePages)?;
+    chk_manage_page(&conn, &user, r_to_page, Permission::ManagePages)?;
+
+    let moved = conn.transaction(|| {
+        chk_move_target(&conn, r_to_page)?;
+        let moved = move_comments(&conn, path_param.page, r_to_page, r_comment)?;
+        audit::record(
+            &conn,
+            &user,
+            "comment.move",
+            r_comment.unwrap_or(path_param.page),
+            json!({
+                "from_page_id": path_param.page,
+                "to_page_id": r_to_page,
+                "moved_comments": moved,
+            }),
+        )?;
+        Ok::<_, AppError>(moved)
+    })?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "moved": moved,
+    })))
+}
+
+/// Keeps the current version as a revision and replaces it.
+fn replace_comment_content(
+    conn: &MainDbPooledConnection,
+    tgt_comment_id: uuid::Uuid,
+    r_content: String,
+    r_content_html: String,
+    editor: Option<uuid::Uuid>,
+) -> AppResult<Comment> {
+    conn.transaction(|| {
+        // locked so that concurrent edits each keep the version they replace
+        let comment = comments
+            .find(tgt_comment_id)
+            .for_update()
+            .first::<Comment>(conn)?;
+
+        if (comment.flags & MARK_AS_DELETED_FRAG_BIT) == MARK_AS_DELETED_FRAG_BIT {
+            return Err(AppError::NotFoundErr("comment not found".to_string()));
+        }
+
+        let now = Utc::now();
+
+        diesel::insert_into(comment_revisions::table)
+            .values(NewCommentRevision {
+                id: uuid::Uuid::new_v4(),
+                comment_id: comment.id,
+                content: comment.content,
+                content_html: comment.content_html,
+                created_time: comment.edited_time.unwrap_or(comment.created_time),
+                replaced_time: now,
+                replaced_by_user_id: editor,
+            })
+            .execute(conn)?;
+
+        let comment_edited = diesel::update(comments.find(comment.id))
+            .set((
+                content.eq(r_content),
+                content_html.eq(r_content_html),
+                edited_time.eq(Some(now)),
+            ))
+            .get_result::<Comment>(conn)?;
+
+        Ok(comment_edited)
+    })
+}
+
+pub async fn edit_comment(
+    db: web::Data<Pool>,
+    path_param: web::Path<EditCommentRequestPath>,
+    edit_request: web::Json<EditCommentRequest>,
+    markdown: web::Data<MarkdownRenderer>,
+    user: Option<AuthUser>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    let EditCommentRequest {
+        delete_key: r_delete_key,
+        content: r_content,
+    } = edit_request.into_inner();
+
+    if r_content.is_empty() {
+        return Err(AppError::PublishableErr(
+            "Comment text is required.".to_owned(),
+        ));
+    }
+
+    let is_manager = can_manage_page(&conn, &user, path_param.page, Permission::ModerateComments)?;
+
+    let (comment, editor) = match r_delete_key {
+        Some(key) => (
+            chk_delete_key(&conn, path_param.page, path_param.comment, &key)?,
+            None,
+        ),
+        None => {
+            let user = user.ok_or_else(|| {
+                AppError::ForbiddenErr("The delete key is required.".to_string())
+            })?;
+            chk_manage_page(&conn, &user, path_param.page, Permission::ModerateComments)?;
+            (
+                find_live_comment(&conn, path_param.page, path_param.comment)?,
+                Some(user.id),
+            )
         }
     };
 
+    chk_page_not_archived(&conn, path_param.page)?;
+    if !is_manager && is_thread_locked(&conn, comment.id)? {
+        return Err(AppError::PublishableErr(
+            "This thread is locked.".to_string(),
+        ));
+    }
+
+    if comment.content == r_content {
+        return Ok(HttpResponse::Ok().json(GetCommentResponse::from(comment)));
+    }
+
+    let r_content_html = markdown.render(&r_content);
+    let comment_edited = replace_comment_content(&conn, comment.id, r_content, r_content_html, editor)?;
+
+    Ok(HttpResponse::Ok().json(GetCommentResponse::from(comment_edited)))
+}
+
+/// Newest first.
+pub async fn get_comment_revisions(
+    db: web::Data<Pool>,
+    path_param: web::Path<CommentRevisionsRequestPath>,
+    user: AuthUser,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    chk_manage_page(&conn, &user, path_param.page, Permission::ModerateComments)?;
+    let comment = find_live_comment(&conn, path_param.page, path_param.comment)?;
+
+    let result = comment_revisions::table
+        .filter(comment_revisions::comment_id.eq(comment.id))
+        .order(comment_revisions::replaced_time.desc())
+        .load::<CommentRevision>(&conn)?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// The version being replaced becomes a revision too, so a restore can be undone.
+pub async fn restore_comment_revision(
+    db: web::Data<Pool>,
+    path_param: web::Path<RestoreCommentRevisionRequestPath>,
+    markdown: web::Data<MarkdownRenderer>,
+    user: AuthUser,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    chk_manage_page(&conn, &user, path_param.page, Permission::ModerateComments)?;
+    chk_page_not_archived(&conn, path_param.page)?;
+    let comment = find_live_comment(&conn, path_param.page, path_param.comment)?;
+
+    let revision = comment_revisions::table
+        .filter(comment_revisions::id.eq(path_param.revision))
+        .filter(comment_revisions::comment_id.eq(comment.id))
+        .first::<CommentRevision>(&conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFoundErr("revision not found".to_string()))?;
+
+    // rendered again, as the settings may have changed since the revision was made
+    let r_content_html = markdown.render(&revision.content);
+    let comment_restored = replace_comment_content(
+        &conn,
+        comment.id,
+        revision.content,
+        r_content_html,
+        Some(user.id),
+    )?;
+
+    Ok(HttpResponse::Ok().json(GetCommentResponse::from(comment_restored)))
+}
+
+pub async fn get_comment_count(
+    db: web::Data<Pool>,
+    path_param: web::Path<GetCommentsRequestPath>,
+    query_param: web::Query<GetCommentsRequestQuery>,
+    user: Option<AuthUser>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    let show_all = can_manage_page(&conn, &user, path_param.page, Permission::ViewPrivate)?;
+    if !show_all {
+        chk_page_public(&conn, path_param.page)?;
+    }
+
+    let result = count_comments(
+        &conn,
+        path_param.page,
+        query_param.replyto,
+        query_param.contextof,
+        show_all,
+    )?;
+
     Ok(HttpResponse::Ok().json(json!({
         "count": result,
     })))
@@ -0,0 +1,528 @@
+use std::env;
+use std::future::{ready, Ready};
+use std::sync::{Arc, Mutex};
+
+use crate::db::Pool;
+use crate::error::{AppError, AppResult, SimpleError};
+use crate::models::Commenter;
+use crate::schema::{commenter_sessions, commenters};
+use crate::utils::{env_or, escape_html, sha256_hex};
+use actix_session::Session;
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+pub const COMMENTER_TOKEN_HEADER: &str = "X-Commenter-Token";
+
+const OIDC_STATE_KEY: &str = "oidc_state";
+const OIDC_NONCE_KEY: &str = "oidc_nonce";
+const RANDOM_LENGTH: usize = 40;
+const MAX_DISPLAY_NAME_LENGTH: usize = 256;
+const MAX_AVATAR_URL_LENGTH: usize = 2048;
+const MAX_PROVIDER_RESPONSE_BYTES: usize = 1024 * 1024;
+const DEFAULT_PROVIDER_NAME: &str = "OpenID Connect";
+const DEFAULT_SCOPES: &str = "openid profile";
+const DEFAULT_SESSION_DAYS: i64 = 30;
+
+/// The columns of `commenters` loaded into `Commenter`.
+const COMMENTER_COLUMNS: (
+    commenters::id,
+    commenters::display_name,
+    commenters::avatar_url,
+) = (
+    commenters::id,
+    commenters::display_name,
+    commenters::avatar_url,
+);
+
+#[derive(Insertable)]
+#[table_name = "commenters"]
+pub struct NewCommenter {
+    id: uuid::Uuid,
+    issuer: String,
+    subject: String,
+    display_name: String,
+    avatar_url: Option<String>,
+    created_time: DateTime<Utc>,
+    last_login_time: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "commenter_sessions"]
+pub struct NewCommenterSession {
+    id: uuid::Uuid,
+    commenter_id: uuid::Uuid,
+    token_hash: String,
+    created_time: DateTime<Utc>,
+    expires_time: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+struct OidcEndpoints {
+    authorization_endpoint: url::Url,
+    token_endpoint: url::Url,
+}
+
+/// A generic OpenID Connect provider commenters can sign in with. Its endpoints are discovered
+/// on the first sign-in.
+pub struct OidcProvider {
+    name: String,
+    issuer: String,
+    client_id: String,
+    client_secret: String,
+    redirect_url: url::Url,
+    scopes: String,
+    front_origin: String,
+    session_lifetime: Duration,
+    endpoints: Mutex<Option<Arc<OidcEndpoints>>>,
+}
+
+/// A commenter signed in through the provider, identified by the `X-Commenter-Token` header.
+pub struct AuthCommenter {
+    pub session_id: uuid::Uuid,
+    pub commenter: Commenter,
+}
+
+fn oidc_err(msg: &str) -> AppError {
+    AppError::AuthErr(format!("Sign-in failed: {}", msg))
+}
+
+fn random_string() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), RANDOM_LENGTH)
+}
+
+fn trim_issuer(issuer: &str) -> &str {
+    issuer.trim_end_matches('/')
+}
+
+/// Plain http is only for a provider on a development machine.
+fn chk_url_allowed(target: &url::Url) -> AppResult<()> {
+    let is_allowed = target.scheme() == "https"
+        || (target.scheme() == "http" && env_or("MODE", "production".to_string()) == "development");
+    if !is_allowed {
+        return Err(SimpleError(format!("{} must use https", target)).into());
+    }
+    Ok(())
+}
+
+/// Sends a GET, or a form POST if `form` is given, and returns the JSON body of a 2xx response.
+async fn request_json(target: &url::Url, form: Option<&[(&str, &str)]>) -> AppResult<Value> {
+    chk_url_allowed(target)?;
+
+    let client = awc::Client::builder()
+        .timeout(std::time::Duration::from_secs(env_or(
+            "HTTP_CLIENT_TIMEOUT_SECS",
+            10,
+        )))
+        .finish();
+    let res = match form {
+        Some(form) => {
+            client
+                .post(target.as_str())
+                .insert_header((header::ACCEPT, "application/json"))
+                .send_form(&form)
+                .await
+        }
+        None => {
+            client
+                .get(target.as_str())
+                .insert_header((header::ACCEPT, "application/json"))
+                .send()
+                .await
+        }
+    };
+    let mut res = res.map_err(|e| SimpleError(format!("{} cannot be reached: {}", target, e)))?;
+
+    if !res.status().is_success() {
+        let body = res
+            .body()
+            .limit(MAX_PROVIDER_RESPONSE_BYTES)
+            .await
+            .unwrap_or_default();
+        return Err(SimpleError(format!(
+            "{} answered {}: {}",
+            target,
+            res.status(),
+            String::from_utf8_lossy(&body)
+        ))
+        .into());
+    }
+
+    Ok(res
+        .json::<Value>()
+        .limit(MAX_PROVIDER_RESPONSE_BYTES)
+        .await?)
+}
+
+impl OidcProvider {
+    /// A provider with the default name, scopes and session lifetime, whose sign-in popup
+    /// talks to the origin of `redirect_url`.
+    pub fn new(issuer: &str, client_id: &str, client_secret: &str, redirect_url: url::Url) -> Self {
+        OidcProvider {
+            name: DEFAULT_PROVIDER_NAME.to_string(),
+            issuer: trim_issuer(issuer).to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            front_origin: redirect_url.origin().ascii_serialization(),
+            redirect_url,
+            scopes: DEFAULT_SCOPES.to_string(),
+            session_lifetime: Duration::days(DEFAULT_SESSION_DAYS),
+            endpoints: Mutex::new(None),
+        }
+    }
+
+    /// `None` unless `OIDC_ISSUER` is set.
+    pub fn from_env() -> Option<Self> {
+        let issuer = env::var("OIDC_ISSUER").ok().filter(|v| !v.is_empty())?;
+        let redirect_url = env::var("OIDC_REDIRECT_URL").expect("OIDC_REDIRECT_URL must be set");
+        let redirect_url = url::Url::parse(&redirect_url).expect("OIDC_REDIRECT_URL is invalid");
+
+        let mut provider = OidcProvider::new(
+            &issuer,
+            &env::var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID must be set"),
+            &env::var("OIDC_CLIENT_SECRET").unwrap_or_default(),
+            redirect_url,
+        );
+        provider.name = env_or("OIDC_PROVIDER_NAME", DEFAULT_PROVIDER_NAME.to_string());
+        provider.scopes = env_or("OIDC_SCOPES", DEFAULT_SCOPES.to_string());
+        provider.session_lifetime =
+            Duration::days(env_or("COMMENTER_SESSION_DAYS", DEFAULT_SESSION_DAYS));
+        if env_or("MODE", "production".to_string()) == "development" {
+            provider.front_origin = env_or("FRONT_ORIGIN", "http://127.0.0.1:5173".to_string());
+        }
+
+        Some(provider)
+    }
+
+    async fn discover(&self) -> AppResult<Arc<OidcEndpoints>> {
+        if let Some(endpoints) = self.endpoints.lock().unwrap().as_ref() {
+            return Ok(endpoints.clone());
+        }
+
+        let discovery_url = url::Url::parse(&format!(
+            "{}/.well-known/openid-configuration",
+            self.issuer
+        ))?;
+        let discovered = request_json(&discovery_url, None).await?;
+
+        if discovered["issuer"].as_str().map(trim_issuer) != Some(self.issuer.as_str()) {
+            return Err(oidc_err("the discovered issuer does not match OIDC_ISSUER"));
+        }
+        let endpoint = |key: &str| -> AppResult<url::Url> {
+            let found = discovered[key]
+                .as_str()
+                .ok_or_else(|| oidc_err(&format!("{} is not discovered", key)))?;
+            let found = url::Url::parse(found)?;
+            chk_url_allowed(&found)?;
+            Ok(found)
+        };
+        let endpoints = Arc::new(OidcEndpoints {
+            authorization_endpoint: endpoint("authorization_endpoint")?,
+            token_endpoint: endpoint("token_endpoint")?,
+        });
+
+        *self.endpoints.lock().unwrap() = Some(endpoints.clone());
+        Ok(endpoints)
+    }
+
+    /// Exchanges the code for an ID token and returns its validated claims.
+    ///
+    /// The token comes straight from the token endpoint over TLS, which OpenID Connect Core
+    /// (3.1.3.7) accepts in place of checking its signature.
+    async fn exchange_code(&self, code: &str, nonce: &str) -> AppResult<Value> {
+        let endpoints = self.discover().await?;
+        let token_response = request_json(
+            &endpoints.token_endpoint,
+            Some(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", self.redirect_url.as_str()),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+            ]),
+        )
+        .await?;
+
+        let id_token = token_response["id_token"]
+            .as_str()
+            .ok_or_else(|| oidc_err("no ID token was returned"))?;
+        let payload = id_token
+            .split('.')
+            .nth(1)
+            .ok_or_else(|| oidc_err("the ID token is malformed"))?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| oidc_err("the ID token is malformed"))?;
+        let claims: Value = serde_json::from_slice(&payload)?;
+
+        if claims["iss"].as_str().map(trim_issuer) != Some(self.issuer.as_str()) {
+            return Err(oidc_err("the ID token is from another issuer"));
+        }
+        let audience_matches = match &claims["aud"] {
+            Value::String(aud) => *aud == self.client_id,
+            Value::Array(auds) => auds.iter().any(|aud| aud.as_str() == Some(&self.client_id)),
+            _ => false,
+        };
+        if !audience_matches {
+            return Err(oidc_err("the ID token is for another client"));
+        }
+        if claims["exp"].as_i64().unwrap_or(0) <= Utc::now().timestamp() {
+            return Err(oidc_err("the ID token has expired"));
+        }
+        if claims["nonce"].as_str() != Some(nonce) {
+            return Err(oidc_err("the nonce does not match"));
+        }
+        if claims["sub"].as_str().is_none_or(|sub| sub.is_empty()) {
+            return Err(oidc_err("the ID token has no subject"));
+        }
+
+        Ok(claims)
+    }
+}
+
+impl FromRequest for AuthCommenter {
+    type Error = AppError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(load_auth_commenter(req))
+    }
+}
+
+fn load_auth_commenter(req: &HttpRequest) -> AppResult<AuthCommenter> {
+    const AUTH_ERR_MSG: &str = "sign-in required";
+
+    let raw_token = req
+        .headers()
+        .get(COMMENTER_TOKEN_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(|| AppError::AuthErr(AUTH_ERR_MSG.to_string()))?;
+
+    let db = req
+        .app_data::<web::Data<Pool>>()
+        .ok_or(AppError::UnspecifiedErr)?;
+    let conn = db.get()?;
+
+    let (session_id, commenter) = commenter_sessions::table
+        .inner_join(commenters::table)
+        .filter(commenter_sessions::token_hash.eq(sha256_hex(raw_token.trim())))
+        .filter(commenter_sessions::expires_time.gt(Utc::now()))
+        .select((commenter_sessions::id, COMMENTER_COLUMNS))
+        .first::<(uuid::Uuid, Commenter)>(&conn)
+        .optional()?
+        .ok_or_else(|| AppError::AuthErr(AUTH_ERR_MSG.to_string()))?;
+
+    Ok(AuthCommenter {
+        session_id,
+        commenter,
+    })
+}
+
+fn display_name_of(claims: &Value) -> String {
+    ["name", "preferred_username", "nickname"]
+        .iter()
+        .filter_map(|key| claims[*key].as_str())
+        .map(|v| v.trim())
+        .find(|v| !v.is_empty())
+        .unwrap_or("(anonymous)")
+        .chars()
+        .take(MAX_DISPLAY_NAME_LENGTH)
+        .collect()
+}
+
+fn avatar_url_of(claims: &Value) -> Option<String> {
+    let picture = claims["picture"].as_str()?;
+    let parsed = url::Url::parse(picture).ok()?;
+    if parsed.scheme() != "https" && parsed.scheme() != "http" {
+        return None;
+    }
+    Some(parsed.to_string()).filter(|v| v.len() <= MAX_AVATAR_URL_LENGTH)
+}
+
+/// The page shown in the sign-in popup. It hands the result to the comment form which opened it.
+fn popup_response(front_origin: &str, message: Value) -> HttpResponse {
+    let message = serde_json::to_string(&message)
+        .unwrap_or_default()
+        .replace('<', "\\u003c");
+    let front_origin = serde_json::to_string(front_origin)
+        .unwrap_or_default()
+        .replace('<', "\\u003c");
+
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>Masacarri</title></head>
+<body><p>You can close this window.</p>
+<script>
+if (window.opener) {{
+  window.opener.postMessage({}, {});
+  window.close();
+}}
+</script>
+</body></html>
+"#,
+            message, front_origin
+        ))
+}
+
+fn popup_error_response(err: &AppError) -> HttpResponse {
+    log::warn!("{}", err);
+    HttpResponse::Unauthorized()
+        .content_type("text/html; charset=utf-8")
+        .body(format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Masacarri</title></head>\n<body><p>{}</p></body></html>\n",
+            escape_html(&err.public_message())
+        ))
+}
+
+/// Whether commenters can sign in, for the comment form.
+pub async fn get_commenter_provider(
+    oidc: web::Data<Option<OidcProvider>>,
+) -> AppResult<impl Responder> {
+    Ok(HttpResponse::Ok().json(match oidc.as_ref() {
+        Some(provider) => json!({ "enabled": true, "name": provider.name }),
+        None => json!({ "enabled": false }),
+    }))
+}
+
+fn provider_of(oidc: &web::Data<Option<OidcProvider>>) -> AppResult<&OidcProvider> {
+    match oidc.get_ref() {
+        Some(provider) => Ok(provider),
+        None => Err(AppError::NotFoundErr("sign-in is not enabled".to_string())),
+    }
+}
+
+/// Opened in a popup by the comment form. Redirects to the provider.
+pub async fn begin_commenter_login(
+    session: Session,
+    oidc: web::Data<Option<OidcProvider>>,
+) -> AppResult<impl Responder> {
+    let provider = provider_of(&oidc)?;
+    let endpoints = provider.discover().await?;
+
+    let state = random_string();
+    let nonce = random_string();
+    session.insert(OIDC_STATE_KEY, &state)?;
+    session.insert(OIDC_NONCE_KEY, &nonce)?;
+
+    let mut location = endpoints.authorization_endpoint.clone();
+    location
+        .query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &provider.client_id)
+        .append_pair("redirect_uri", provider.redirect_url.as_str())
+        .append_pair("scope", &provider.scopes)
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce);
+
+    Ok(HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, location.to_string()))
+        .finish())
+}
+
+/// The redirect URL registered at the provider.
+pub async fn finish_commenter_login(
+    session: Session,
+    db: web::Data<Pool>,
+    oidc: web::Data<Option<OidcProvider>>,
+    query_param: web::Query<OidcCallbackQuery>,
+) -> AppResult<impl Responder> {
+    let provider = provider_of(&oidc)?;
+
+    let expected_state = session.remove_as::<String>(OIDC_STATE_KEY).and_then(Result::ok);
+    let nonce = session.remove_as::<String>(OIDC_NONCE_KEY).and_then(Result::ok);
+
+    let OidcCallbackQuery {
+        code: r_code,
+        state: r_state,
+        error: r_error,
+    } = query_param.into_inner();
+
+    let (code, nonce) = match (r_error, r_code, nonce) {
+        (Some(e), _, _) => return Ok(popup_error_response(&oidc_err(&e))),
+        (None, Some(code), Some(nonce)) if expected_state.is_some() && r_state == expected_state => {
+            (code, nonce)
+        }
+        _ => return Ok(popup_error_response(&oidc_err("the state does not match"))),
+    };
+
+    let claims = match provider.exchange_code(&code, &nonce).await {
+        Ok(claims) => claims,
+        Err(e) => return Ok(popup_error_response(&e)),
+    };
+
+    let conn = db.get()?;
+    let now = Utc::now();
+    let token = random_string();
+
+    let commenter = conn.transaction::<_, AppError, _>(|| {
+        let commenter = diesel::insert_into(commenters::table)
+            .values(NewCommenter {
+                id: uuid::Uuid::new_v4(),
+                issuer: provider.issuer.clone(),
+                subject: claims["sub"].as_str().unwrap_or_default().to_string(),
+                display_name: display_name_of(&claims),
+                avatar_url: avatar_url_of(&claims),
+                created_time: now,
+                last_login_time: now,
+            })
+            .on_conflict((commenters::issuer, commenters::subject))
+            .do_update()
+            .set((
+                commenters::display_name.eq(display_name_of(&claims)),
+                commenters::avatar_url.eq(avatar_url_of(&claims)),
+                commenters::last_login_time.eq(now),
+            ))
+            .returning(COMMENTER_COLUMNS)
+            .get_result::<Commenter>(&conn)?;
+
+        diesel::delete(commenter_sessions::table.filter(commenter_sessions::expires_time.lt(now)))
+            .execute(&conn)?;
+        diesel::insert_into(commenter_sessions::table)
+            .values(NewCommenterSession {
+                id: uuid::Uuid::new_v4(),
+                commenter_id: commenter.id,
+                token_hash: sha256_hex(&token),
+                created_time: now,
+                expires_time: now + provider.session_lifetime,
+            })
+            .execute(&conn)?;
+
+        Ok(commenter)
+    })?;
+
+    Ok(popup_response(
+        &provider.front_origin,
+        json!({
+            "type": "masacarri-commenter",
+            "token": token,
+            "commenter": commenter,
+        }),
+    ))
+}
+
+pub async fn get_current_commenter(commenter: AuthCommenter) -> AppResult<impl Responder> {
+    Ok(HttpResponse::Ok().json(commenter.commenter))
+}
+
+pub async fn end_commenter_session(
+    commenter: AuthCommenter,
+    db: web::Data<Pool>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    diesel::delete(commenter_sessions::table.find(commenter.session_id)).execute(&conn)?;
+
+    Ok(HttpResponse::NoContent())
+}
@@ -0,0 +1,433 @@
+use std::fmt::Write;
+
+use crate::auth::{can_manage_page, AuthUser, Permission};
+use crate::bgtask::BgTaskManager;
+use crate::client_ip::ClientIp;
+use crate::commenter::AuthCommenter;
+use crate::comment::{
+    count_comments, load_comments, locked_comment_ids, post_comment, CommentSort,
+    GetCommentResponse, NewCommentRequest,
+};
+use crate::db::Pool;
+use crate::error::{AppError, AppResult};
+use crate::markdown::MarkdownRenderer;
+use crate::models::{Comment, CommentStatus, Page, Site};
+use crate::page::{find_or_register_page, request_origin, AutoRegisterPrefixes};
+use crate::ratelimit::RateLimiter;
+use crate::schema::{comments, pages, sites};
+use crate::spamfilter::SpamFilterPipeline;
+use crate::utils::escape_html;
+use actix_web::http::{header, StatusCode};
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use diesel::prelude::*;
+use serde::Deserialize;
+
+const EMBED_COMMENTS_PER_PAGE: u32 = 50;
+
+const EMBED_STYLE: &str = r#"
+body { font-family: sans-serif; margin: 0; padding: 8px; color: #222; }
+.comment { border-bottom: 1px solid #ddd; padding: 8px 0; }
+.comment header { font-size: 0.9em; color: #555; }
+.comment .name { font-weight: bold; color: #222; }
+.comment footer { font-size: 0.85em; }
+.notice { background: #f4f4d0; padding: 8px; }
+.pagination { display: flex; justify-content: space-between; padding: 8px 0; }
+form label { display: block; margin: 8px 0; }
+form input, form textarea { display: block; width: 100%; box-sizing: border-box; }
+form textarea { min-height: 8em; }
+.hp { position: absolute; left: -10000px; width: 1px; height: 1px; overflow: hidden; }
+"#;
+
+#[derive(Deserialize)]
+pub struct EmbedRequestPath {
+    page: uuid::Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct EmbedRequestQuery {
+    index: Option<u32>,
+    replyto: Option<uuid::Uuid>,
+    posted: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EmbedByUrlRequestQuery {
+    url: String,
+    title: Option<String>,
+}
+
+fn html_document(title: &str, body: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{}</title>
+<style>{}</style>
+</head>
+<body>
+{}
+</body>
+</html>
+"#,
+        escape_html(title),
+        EMBED_STYLE,
+        body
+    )
+}
+
+fn error_page(e: &AppError, back_to: Option<&str>) -> HttpResponse {
+    let mut body = format!(
+        r#"<p class="notice">{}</p>"#,
+        escape_html(&e.public_message())
+    );
+    if let Some(back_to) = back_to {
+        write!(body, r#"<p><a href="{}">Back</a></p>"#, escape_html(back_to)).unwrap();
+    }
+
+    HttpResponse::build(e.status_code())
+        .content_type("text/html; charset=utf-8")
+        .body(html_document("Error", &body))
+}
+
+/// `is_locked` also holds for comments below a locked one, which cannot be replied to either.
+fn render_comment(
+    out: &mut String,
+    comment: &GetCommentResponse,
+    reply_link: &str,
+    is_locked: bool,
+) {
+    let name = escape_html(&comment.display_name);
+    let name = match &comment.site_url {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => format!(
+            r#"<a class="name" href="{}" rel="nofollow noopener ugc">{}</a>"#,
+            escape_html(url),
+            name
+        ),
+        _ => format!(r#"<span class="name">{}</span>"#, name),
+    };
+    let name = if comment.author {
+        format!(r#"{} <span class="author">author</span>"#, name)
+    } else if comment.verified {
+        format!(r#"{} <span class="verified" title="signed in">&#10004;</span>"#, name)
+    } else {
+        name
+    };
+
+    write!(
+        out,
+        r#"<article class="comment" id="comment-{id}">
+<header>{name} <time datetime="{datetime}">{date}</time>"#,
+        id = comment.id,
+        name = name,
+        datetime = comment.created_time.to_rfc3339(),
+        date = comment.created_time.format("%Y-%m-%d %H:%M"),
+    )
+    .unwrap();
+    if let Some(reply_to) = comment.reply_to {
+        write!(out, r##" <a href="#comment-{}">in reply to</a>"##, reply_to).unwrap();
+    }
+    if comment.status != CommentStatus::Approved {
+        write!(out, " ({})", comment.status.as_str()).unwrap();
+    }
+    if comment.edited {
+        write!(out, " (edited)").unwrap();
+    }
+    if comment.is_pinned.is_some() {
+        write!(out, " (pinned)").unwrap();
+    }
+    let reply = if is_locked {
+        "Locked".to_string()
+    } else {
+        format!(r#"<a href="{}">Reply</a>"#, escape_html(reply_link))
+    };
+    write!(
+        out,
+        r#"</header>
+<div class="content">{content}</div>
+<footer>{reply} ({replies} replies){reactions}</footer>
+</article>
+"#,
+        content = comment.content_html,
+        reply = reply,
+        replies = comment.count_replies.unwrap_or(0),
+        reactions = comment
+            .reactions
+            .iter()
+            .flatten()
+            .map(|(kind, n)| format!(" {}: {}", escape_html(kind), n))
+            .collect::<String>(),
+    )
+    .unwrap();
+}
+
+fn render_form(out: &mut String, tgt_page_id: uuid::Uuid, index: u32, replyto: Option<&GetCommentResponse>) {
+    writeln!(
+        out,
+        r#"<form id="comment-form" method="post" action="/embed/{}/comments">"#,
+        tgt_page_id
+    )
+    .unwrap();
+    if let Some(replyto) = replyto {
+        write!(
+            out,
+            r##"<p>Replying to <a href="#comment-{id}">{name}</a> (<a href="/embed/{page}?index={index}#comment-form">cancel</a>)</p>
+<input type="hidden" name="reply_to" value="{id}">
+"##,
+            id = replyto.id,
+            name = escape_html(&replyto.display_name),
+            page = tgt_page_id,
+            index = index,
+        )
+        .unwrap();
+    }
+    out.push_str(
+        r#"<label>Name <input name="display_name" required maxlength="1024"></label>
+<label>Website (optional) <input name="site_url" type="url" maxlength="1024"></label>
+<label>Mail address, notified on replies and never shown (optional) <input name="mail_addr" type="email" maxlength="1024"></label>
+<label>Comment (Markdown) <textarea name="content" required maxlength="16384"></textarea></label>
+<label>Delete key, to delete or edit later (optional) <input name="delete_key" type="password"></label>
+<div class="hp" aria-hidden="true"><label>Leave this empty <input name="honeypot" tabindex="-1" autocomplete="off"></label></div>
+<button type="submit">Post</button>
+</form>
+"#,
+    );
+}
+
+/// Returns the document and the origins allowed to frame it.
+fn render_embed(
+    db: &Pool,
+    tgt_page_id: uuid::Uuid,
+    query_param: &EmbedRequestQuery,
+    user: &Option<AuthUser>,
+) -> AppResult<(String, Vec<String>)> {
+    let conn = db.get()?;
+
+    let (page, site) = pages::table
+        .inner_join(sites::table)
+        .filter(pages::id.eq(tgt_page_id))
+        .first::<(Page, Site)>(&conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFoundErr("page not found".to_string()))?;
+
+    let show_all = can_manage_page(&conn, user, tgt_page_id, Permission::ViewPrivate)?;
+
+    if !page.published && !show_all {
+        return Err(AppError::PublishableErr(
+            "This page is private.".to_string(),
+        ));
+    }
+
+    let index = query_param.index.unwrap_or(1).max(1);
+
+    let count = count_comments(&conn, tgt_page_id, None, None, show_all)?;
+    let last_index = (count as u32).div_ceil(EMBED_COMMENTS_PER_PAGE);
+
+    let shown: Vec<GetCommentResponse> = load_comments(
+        &conn,
+        tgt_page_id,
+        None,
+        None,
+        EMBED_COMMENTS_PER_PAGE,
+        index - 1,
+        show_all,
+        CommentSort::Oldest,
+    )?
+    .into_iter()
+    .map(GetCommentResponse::from)
+    .collect();
+
+    let replyto = match query_param.replyto {
+        Some(reply_id) => comments::table
+            .filter(comments::page_id.eq(tgt_page_id))
+            .filter(comments::id.eq(reply_id))
+            .first::<Comment>(&conn)
+            .optional()?
+            .filter(|c| show_all || c.status == CommentStatus::Approved)
+            .map(GetCommentResponse::from),
+        None => None,
+    };
+
+    let mut body = String::new();
+    body.push_str(r#"<main class="masacarri">"#);
+    writeln!(body, "<h1>{}</h1>", escape_html(&page.title)).unwrap();
+
+    if query_param.posted.as_deref() == Some("pending") {
+        body.push_str(
+            r#"<p class="notice">Thank you. Your comment will appear once it has been approved.</p>"#,
+        );
+    }
+
+    let locked = locked_comment_ids(&conn, tgt_page_id)?;
+
+    body.push_str(r#"<section class="comments">"#);
+    if shown.is_empty() {
+        body.push_str("<p>No comments yet.</p>\n");
+    }
+    for comment in shown.iter() {
+        let reply_link = format!(
+            "/embed/{}?index={}&replyto={}#comment-form",
+            tgt_page_id, index, comment.id
+        );
+        render_comment(
+            &mut body,
+            comment,
+            &reply_link,
+            locked.contains(&comment.id),
+        );
+    }
+    body.push_str("</section>\n");
+
+    if last_index > 1 {
+        body.push_str(r#"<nav class="pagination">"#);
+        if index > 1 {
+            write!(
+                body,
+                r#"<a href="/embed/{}?index={}" rel="prev">Older comments</a>"#,
+                tgt_page_id,
+                index - 1
+            )
+            .unwrap();
+        } else {
+            body.push_str("<span></span>");
+        }
+        if index < last_index {
+            write!(
+                body,
+                r#"<a href="/embed/{}?index={}" rel="next">Newer comments</a>"#,
+                tgt_page_id,
+                index + 1
+            )
+            .unwrap();
+        }
+        body.push_str("</nav>\n");
+    }
+
+    // the form posts without the commenter token, so only pages open to anyone take it
+    let is_moderator = can_manage_page(&conn, user, tgt_page_id, Permission::ModerateComments)?;
+    if page.archived {
+        body.push_str(r#"<p class="notice">This page is archived.</p>"#);
+    } else if !page.allow_anonymous && !is_moderator {
+        body.push_str(r#"<p class="notice">Only signed-in commenters can comment on this page.</p>"#);
+    } else {
+        render_form(&mut body, tgt_page_id, index, replyto.as_ref());
+    }
+    body.push_str("</main>");
+
+    Ok((html_document(&page.title, &body), site.allowed_origins))
+}
+
+pub async fn get_embed(
+    db: web::Data<Pool>,
+    path_param: web::Path<EmbedRequestPath>,
+    query_param: web::Query<EmbedRequestQuery>,
+    user: Option<AuthUser>,
+) -> HttpResponse {
+    match render_embed(&db, path_param.page, &query_param, &user) {
+        Ok((html, allowed_origins)) => {
+            let mut response = HttpResponse::Ok();
+            response.content_type("text/html; charset=utf-8");
+            // sites without allowed origins may be framed from anywhere
+            if !allowed_origins.is_empty() {
+                response.insert_header((
+                    header::CONTENT_SECURITY_POLICY,
+                    format!("frame-ancestors 'self' {}", allowed_origins.join(" ")),
+                ));
+            }
+            response.body(html)
+        }
+        Err(e) => error_page(&e, None),
+    }
+}
+
+/// Lets the embed snippet name the article by its URL instead of the page id.
+pub async fn get_embed_by_url(
+    request: HttpRequest,
+    db: web::Data<Pool>,
+    query_param: web::Query<EmbedByUrlRequestQuery>,
+    auto_register: web::Data<AutoRegisterPrefixes>,
+    user: Option<AuthUser>,
+) -> HttpResponse {
+    let EmbedByUrlRequestQuery {
+        url: r_url,
+        title: r_title,
+    } = query_param.into_inner();
+
+    let res = db.get().map_err(AppError::from).and_then(|conn| {
+        find_or_register_page(
+            &conn,
+            &r_url,
+            r_title,
+            &auto_register,
+            request_origin(&request).as_deref(),
+            &user,
+        )
+    });
+
+    match res {
+        Ok((page, _)) => HttpResponse::build(StatusCode::SEE_OTHER)
+            .insert_header((header::LOCATION, format!("/embed/{}", page.id)))
+            .finish(),
+        Err(e) => error_page(&e, None),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn add_embed_comment(
+    db: web::Data<Pool>,
+    path_param: web::Path<EmbedRequestPath>,
+    client_ip: ClientIp,
+    new_comment: web::Form<NewCommentRequest>,
+    bgtask_manager: web::Data<BgTaskManager>,
+    spam_filter: web::Data<SpamFilterPipeline>,
+    rate_limiter: web::Data<RateLimiter>,
+    markdown: web::Data<MarkdownRenderer>,
+    user: Option<AuthUser>,
+    commenter: Option<AuthCommenter>,
+) -> HttpResponse {
+    let back_to = format!("/embed/{}#comment-form", path_param.page);
+
+    let res = post_comment(
+        &db,
+        path_param.page,
+        client_ip.network(),
+        new_comment.into_inner(),
+        &bgtask_manager,
+        &spam_filter,
+        &rate_limiter,
+        &markdown,
+        &user,
+        &commenter,
+    )
+    .await;
+
+    let comment_new = match res {
+        Ok(comment) => comment,
+        Err(e) => return error_page(&e, Some(&back_to)),
+    };
+
+    let location = if comment_new.status == CommentStatus::Approved {
+        let count = db
+            .get()
+            .map_err(AppError::from)
+            .and_then(|conn| {
+                let show_all = can_manage_page(&conn, &user, path_param.page, Permission::ViewPrivate)?;
+                count_comments(&conn, path_param.page, None, None, show_all)
+            })
+            .unwrap_or(0);
+        let last_index = (count as u32).div_ceil(EMBED_COMMENTS_PER_PAGE);
+        format!(
+            "/embed/{}?index={}#comment-{}",
+            path_param.page,
+            last_index.max(1),
+            comment_new.id
+        )
+    } else {
+        format!("/embed/{}?posted=pending#comment-form", path_param.page)
+    };
+
+    HttpResponse::build(StatusCode::SEE_OTHER)
+        .insert_header((header::LOCATION, location))
+        .finish()
+}
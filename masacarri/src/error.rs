@@ -1,4 +1,7 @@
-use actix_web::{http::StatusCode, HttpResponse};
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse,
+};
 use serde::Serialize;
 use std::fmt::{Display, Formatter};
 
@@ -13,6 +16,9 @@ pub enum AppError {
     StdErr(Box<dyn std::error::Error>),
     PublishableErr(String),
     AuthErr(String),
+    ForbiddenErr(String),
+    NotFoundErr(String),
+    RateLimitErr(u64),
 }
 
 impl Display for AppError {
@@ -22,6 +28,23 @@ impl Display for AppError {
             AppError::StdErr(e) => e.fmt(f),
             AppError::PublishableErr(s) => write!(f, "{}", s),
             AppError::AuthErr(s) => write!(f, "{}", s),
+            AppError::ForbiddenErr(s) => write!(f, "{}", s),
+            AppError::NotFoundErr(s) => write!(f, "{}", s),
+            AppError::RateLimitErr(secs) => write!(f, "rate limited for {} seconds", secs),
+        }
+    }
+}
+
+impl AppError {
+    /// The message which is safe to show to clients.
+    pub fn public_message(&self) -> String {
+        match self {
+            AppError::PublishableErr(s) => s.clone(),
+            AppError::AuthErr(s) => s.clone(),
+            AppError::ForbiddenErr(s) => s.clone(),
+            AppError::NotFoundErr(s) => s.clone(),
+            AppError::RateLimitErr(_) => "Too many requests. Please retry later.".to_string(),
+            _ => "system error".to_string(),
         }
     }
 }
@@ -30,18 +53,21 @@ impl actix_web::error::ResponseError for AppError {
     fn status_code(&self) -> StatusCode {
         match self {
             AppError::AuthErr(_) => StatusCode::UNAUTHORIZED,
+            AppError::ForbiddenErr(_) => StatusCode::FORBIDDEN,
+            AppError::NotFoundErr(_) => StatusCode::NOT_FOUND,
+            AppError::RateLimitErr(_) => StatusCode::TOO_MANY_REQUESTS,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
     fn error_response(&self) -> HttpResponse {
-        let msg = match self {
-            AppError::PublishableErr(s) => s.clone(),
-            AppError::AuthErr(s) => s.clone(),
-            _ => "system error".to_string(),
-        };
+        let msg = self.public_message();
 
         eprintln!("{}", self);
-        HttpResponse::build(self.status_code()).json(ErrorMessage { message: msg })
+        let mut res = HttpResponse::build(self.status_code());
+        if let AppError::RateLimitErr(secs) = self {
+            res.insert_header((header::RETRY_AFTER, secs.to_string()));
+        }
+        res.json(ErrorMessage { message: msg })
     }
 }
 
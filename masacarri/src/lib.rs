@@ -1,12 +1,29 @@
 #[macro_use]
 extern crate diesel;
 
+pub mod apitoken;
+pub mod audit;
+pub mod auth;
+pub mod embed;
 pub mod error;
+pub mod client_ip;
 pub mod comment;
+pub mod commenter;
 pub mod db;
+pub mod loginguard;
+pub mod markdown;
 pub mod models;
+pub mod moderation;
 pub mod page;
+pub mod ratelimit;
+pub mod reaction;
 pub mod schema;
+pub mod sessionkey;
+pub mod site;
+pub mod spamfilter;
 pub mod mail;
+pub mod totp;
+pub mod user;
 pub mod utils;
 pub mod bgtask;
+pub mod ban;
@@ -0,0 +1,217 @@
+use std::time::Duration;
+
+use crate::auth::{AuthUser, Permission};
+use crate::db::{MainDbConnection, Pool};
+use crate::error::{AppError, AppResult};
+use crate::models::{LoginAttempt, LoginResult};
+use crate::schema::login_attempts;
+use crate::schema::login_attempts::dsl::*;
+use crate::utils::env_or;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use diesel::dsl;
+use diesel::prelude::*;
+use serde::Deserialize;
+use static_assertions::const_assert;
+
+const DEFAULT_ITEMS_PER_PAGE: u32 = 50;
+const DEFAULT_PAGE_INDEX: u32 = 1;
+const MAX_ITEMS_PER_PAGE: u32 = 256;
+
+const_assert!(DEFAULT_ITEMS_PER_PAGE <= MAX_ITEMS_PER_PAGE);
+const_assert!(DEFAULT_PAGE_INDEX == 1);
+
+const FAILURE_DELAY_STEP_MS: u64 = 500;
+const MAX_FAILURE_DELAY_MS: u64 = 5000;
+
+#[derive(Insertable)]
+#[table_name = "login_attempts"]
+pub struct NewLoginAttempt {
+    id: uuid::Uuid,
+    username: String,
+    ip_addr: ipnetwork::IpNetwork,
+    result: LoginResult,
+    created_time: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct GetLoginAttemptsQuery {
+    username: Option<String>,
+    ip_addr: Option<ipnetwork::IpNetwork>,
+    result: Option<LoginResult>,
+    num: Option<u32>,
+    index: Option<u32>,
+}
+
+/// Locks a username or an address out after too many failed logins within `lockout`.
+pub struct LoginGuard {
+    max_failures_per_user: u32,
+    max_failures_per_ip: u32,
+    lockout: chrono::Duration,
+}
+
+impl LoginGuard {
+    pub fn from_env() -> Self {
+        LoginGuard {
+            max_failures_per_user: env_or("LOGIN_MAX_FAILURES", 5),
+            max_failures_per_ip: env_or("LOGIN_MAX_FAILURES_PER_IP", 20),
+            lockout: chrono::Duration::seconds(env_or("LOGIN_LOCKOUT_SECS", 900)),
+        }
+    }
+
+    /// Failures of `tgt_username` since its last successful login, newest first.
+    fn recent_user_failures(
+        &self,
+        conn: &MainDbConnection,
+        tgt_username: &str,
+        limit: u32,
+    ) -> AppResult<Vec<DateTime<Utc>>> {
+        let last_success: Option<DateTime<Utc>> = login_attempts
+            .filter(username.eq(tgt_username))
+            .filter(result.eq(LoginResult::Succeeded))
+            .select(dsl::max(created_time))
+            .first(conn)?;
+
+        let window_start = Utc::now() - self.lockout;
+        let since = match last_success {
+            Some(t) if t > window_start => t,
+            _ => window_start,
+        };
+
+        let result_times = login_attempts
+            .filter(username.eq(tgt_username))
+            .filter(result.eq(LoginResult::Failed))
+            .filter(created_time.gt(since))
+            .order(created_time.desc())
+            .limit(limit.into())
+            .select(created_time)
+            .load::<DateTime<Utc>>(conn)?;
+
+        Ok(result_times)
+    }
+
+    fn recent_ip_failures(
+        &self,
+        conn: &MainDbConnection,
+        tgt_ip_addr: ipnetwork::IpNetwork,
+        limit: u32,
+    ) -> AppResult<Vec<DateTime<Utc>>> {
+        let result_times = login_attempts
+            .filter(ip_addr.eq(tgt_ip_addr))
+            .filter(result.eq(LoginResult::Failed))
+            .filter(created_time.gt(Utc::now() - self.lockout))
+            .order(created_time.desc())
+            .limit(limit.into())
+            .select(created_time)
+            .load::<DateTime<Utc>>(conn)?;
+
+        Ok(result_times)
+    }
+
+    /// The lockout lasts until the oldest of the last `max` failures leaves the window.
+    fn retry_after(&self, failures: &[DateTime<Utc>], max_failures: u32) -> Option<u64> {
+        if max_failures == 0 || failures.len() < max_failures as usize {
+            return None;
+        }
+        let oldest = failures[max_failures as usize - 1];
+        let secs = (oldest + self.lockout - Utc::now()).num_seconds();
+        Some(secs.max(1) as u64)
+    }
+
+    pub fn chk_not_locked(
+        &self,
+        conn: &MainDbConnection,
+        tgt_username: &str,
+        tgt_ip_addr: ipnetwork::IpNetwork,
+    ) -> AppResult<()> {
+        let user_failures =
+            self.recent_user_failures(conn, tgt_username, self.max_failures_per_user)?;
+        let ip_failures = self.recent_ip_failures(conn, tgt_ip_addr, self.max_failures_per_ip)?;
+
+        let retry_after = self
+            .retry_after(&user_failures, self.max_failures_per_user)
+            .max(self.retry_after(&ip_failures, self.max_failures_per_ip));
+
+        match retry_after {
+            Some(secs) => Err(AppError::RateLimitErr(secs)),
+            None => Ok(()),
+        }
+    }
+
+    /// Grows with every failure of the username so that guessing slows down before the lockout.
+    pub fn failure_delay(&self, conn: &MainDbConnection, tgt_username: &str) -> AppResult<Duration> {
+        let failures = self
+            .recent_user_failures(conn, tgt_username, self.max_failures_per_user.max(1))?
+            .len() as u64;
+
+        Ok(Duration::from_millis(
+            (failures * FAILURE_DELAY_STEP_MS).min(MAX_FAILURE_DELAY_MS),
+        ))
+    }
+
+    pub fn record(
+        &self,
+        conn: &MainDbConnection,
+        tgt_username: &str,
+        tgt_ip_addr: ipnetwork::IpNetwork,
+        login_result: LoginResult,
+    ) -> AppResult<()> {
+        diesel::insert_into(login_attempts)
+            .values(NewLoginAttempt {
+                id: uuid::Uuid::new_v4(),
+                username: tgt_username.chars().take(256).collect(),
+                ip_addr: tgt_ip_addr,
+                result: login_result,
+                created_time: Utc::now(),
+            })
+            .execute(conn)?;
+
+        Ok(())
+    }
+}
+
+pub async fn get_login_attempts(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    query_param: web::Query<GetLoginAttemptsQuery>,
+) -> AppResult<impl Responder> {
+    user.require(Permission::ManageUsers)?;
+
+    let conn = db.get()?;
+
+    let items_per_page = query_param.num.unwrap_or(DEFAULT_ITEMS_PER_PAGE);
+    let items_page_index = query_param.index.unwrap_or(DEFAULT_PAGE_INDEX);
+
+    if items_page_index < 1 {
+        return Err(AppError::PublishableErr("invalid page index".to_string()));
+    }
+
+    let items_page_index = items_page_index - 1;
+
+    if items_per_page == 0 || items_per_page > MAX_ITEMS_PER_PAGE {
+        return Err(AppError::PublishableErr(format!(
+            "Items per page is limited up to {}.",
+            MAX_ITEMS_PER_PAGE
+        )));
+    }
+
+    let mut query = login_attempts.into_boxed();
+
+    if let Some(tgt_username) = &query_param.username {
+        query = query.filter(username.eq(tgt_username));
+    }
+    if let Some(tgt_ip_addr) = query_param.ip_addr {
+        query = query.filter(ip_addr.eq(tgt_ip_addr));
+    }
+    if let Some(tgt_result) = query_param.result {
+        query = query.filter(result.eq(tgt_result));
+    }
+
+    let attempts = query
+        .order(created_time.desc())
+        .offset((items_per_page * items_page_index).into())
+        .limit(items_per_page.into())
+        .load::<LoginAttempt>(&conn)?;
+
+    Ok(HttpResponse::Ok().json(attempts))
+}
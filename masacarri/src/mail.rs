@@ -8,12 +8,13 @@ use lettre::{
 
 use crate::{
     bgtask::BgActor,
-    db::MainDbPooledConnection,
+    db::Pool,
     error::AppResult,
-    models::{Comment, Page},
+    models::{Comment, Page, Site},
 };
 
 pub async fn notify_reply(
+    site: &Site,
     page: &Page,
     comment_replyto: &Comment,
     comment_reply: &Comment,
@@ -23,8 +24,11 @@ pub async fn notify_reply(
         None => return Ok(()),
     };
 
-    let site_name = env::var("SITE_NAME").unwrap_or("Masacarri".to_string());
-    let mailaddr_from = env::var("SMTP_MAILADDR")?;
+    let site_name = &site.name;
+    let mailaddr_from = match &site.mail_from {
+        Some(x) => x.clone(),
+        None => env::var("SMTP_MAILADDR")?,
+    };
     let smtp_encryption = env::var("SMTP_ENCRYPTION")?;
     let smtp_host = env::var("SMTP_HOST")?;
 
@@ -72,7 +76,7 @@ pub async fn notify_reply(
 #[rtype(result = "()")]
 pub struct MailNotifyTask {
     pub id_replyto: uuid::Uuid,
-    pub conn: MainDbPooledConnection,
+    pub pool: Pool,
     pub comment_new: Comment,
 }
 
@@ -84,23 +88,36 @@ impl actix::Handler<MailNotifyTask> for BgActor {
 
         const NOTIFY_RETRY_NUMBER: i32 = 5;
 
+        let conn = match task.pool.get() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+
         let comment_replyto = comments
             .filter(id.eq(task.id_replyto))
-            .first::<Comment>(&task.conn);
+            .first::<Comment>(&conn);
         let comment_replyto = match comment_replyto {
             Ok(x) => x,
             Err(_) => return,
         };
         let page = crate::schema::pages::dsl::pages
             .filter(crate::schema::pages::id.eq(task.comment_new.page_id))
-            .first::<crate::models::Page>(&task.conn);
+            .first::<crate::models::Page>(&conn);
         let page = match page {
             Ok(x) => x,
             Err(_) => return,
         };
+        let site = crate::schema::sites::dsl::sites
+            .filter(crate::schema::sites::id.eq(page.site_id))
+            .first::<crate::models::Site>(&conn);
+        let site = match site {
+            Ok(x) => x,
+            Err(_) => return,
+        };
 
         for _ in 0..NOTIFY_RETRY_NUMBER {
             let res = actix::System::new().block_on(notify_reply(
+                &site,
                 &page,
                 &comment_replyto,
                 &task.comment_new,
@@ -4,13 +4,14 @@ use std::time::Duration;
 use actix_cors::Cors;
 use actix_files::NamedFile;
 use actix_identity::{Identity, IdentityMiddleware};
-use actix_session::SessionMiddleware;
-use actix_web::dev::{fn_service, ServiceRequest, ServiceResponse};
-use actix_web::middleware::{Condition, Logger};
+use actix_session::{Session, SessionMiddleware};
+use actix_web::dev::{fn_service, Service, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Logger;
 use actix_web::{
-    cookie::Key, http, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
+    http, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use diesel::ExpressionMethods;
+use diesel::OptionalExtension;
 use diesel::QueryDsl;
 use diesel::RunQueryDsl;
 
@@ -19,6 +20,7 @@ extern crate diesel_migrations;
 
 use dotenv::dotenv;
 use error::{AppError, AppResult};
+use models::LoginResult;
 use schema::users;
 use serde::Deserialize;
 use serde_json::json;
@@ -26,18 +28,40 @@ use serde_json::json;
 #[macro_use]
 extern crate diesel;
 
+mod apitoken;
+mod audit;
+mod auth;
+mod ban;
 mod bgtask;
+mod client_ip;
 mod comment;
+mod commenter;
 mod db;
+mod embed;
 mod error;
+mod loginguard;
 mod mail;
+mod markdown;
 mod models;
+mod moderation;
 mod page;
+mod ratelimit;
+mod reaction;
 mod schema;
+mod sessionkey;
+mod site;
+mod spamfilter;
+mod totp;
+mod user;
 mod utils;
+use crate::ban::*;
 use crate::comment::*;
 use crate::db::*;
+use crate::embed::*;
+use crate::moderation::*;
 use crate::page::*;
+use crate::site::*;
+use crate::user::*;
 
 #[derive(Deserialize)]
 struct LoginRequest {
@@ -45,38 +69,125 @@ struct LoginRequest {
     password: String,
 }
 
+#[derive(Deserialize)]
+struct LoginTotpRequest {
+    code: String,
+}
+
+const LOGIN_ERR_MSG: &str = "login failed";
+const TOTP_PENDING_USER_KEY: &str = "totp_pending_user";
+const TOTP_PENDING_SINCE_KEY: &str = "totp_pending_since";
+const TOTP_PENDING_SECS: i64 = 300;
+const SITE_ORIGINS_REFRESH_SECS: u64 = 60;
+
 async fn login(
     request: HttpRequest,
+    session: Session,
     db: web::Data<Pool>,
     login_data: web::Json<LoginRequest>,
+    client_ip: client_ip::ClientIp,
+    login_guard: web::Data<loginguard::LoginGuard>,
 ) -> AppResult<impl Responder> {
     let conn = db.get()?;
 
     let LoginRequest { user, password } = login_data.0;
 
-    const LOGIN_ERR_MSG: &str = "login failed";
+    let ipaddr = client_ip.network();
+    if let Err(e) = login_guard.chk_not_locked(&conn, &user, ipaddr) {
+        login_guard.record(&conn, &user, ipaddr, LoginResult::LockedOut)?;
+        return Err(e);
+    }
 
-    let user_password_hash = users::dsl::users
+    let user_found = users::dsl::users
         .filter(users::dsl::username.eq(user.as_str()))
-        .select((users::dsl::password_hash,))
-        .first::<(String,)>(&conn);
+        .first::<models::User>(&conn)
+        .optional()?
+        .filter(|u| !user::is_user_disabled(u));
 
-    let user_password_hash = if let Ok(h) = user_password_hash {
-        h.0
-    } else {
-        return Err(AppError::AuthErr(LOGIN_ERR_MSG.to_string()));
+    let user_password_hash = match &user_found {
+        Some(u) => u.password_hash.clone(),
+        None => user::dummy_password_hash().to_string(),
     };
 
-    if let Ok(true) = bcrypt::verify(password, &user_password_hash) {
-        Identity::login(&request.extensions(), user.into()).unwrap();
-        Ok(HttpResponse::Ok().json(json! {
-            {
-                "message": "successfully logged in"
-            }
-        }))
-    } else {
+    match (bcrypt::verify(password, &user_password_hash), user_found) {
+        (Ok(true), Some(u)) if totp::is_totp_enabled(&u) => {
+            session.insert(TOTP_PENDING_USER_KEY, u.id)?;
+            session.insert(TOTP_PENDING_SINCE_KEY, chrono::Utc::now().timestamp())?;
+            Ok(HttpResponse::Ok().json(json! {
+                {
+                    "message": "second factor required",
+                    "totp_required": true
+                }
+            }))
+        }
+        (Ok(true), Some(_)) => {
+            login_guard.record(&conn, &user, ipaddr, LoginResult::Succeeded)?;
+            Identity::login(&request.extensions(), user).unwrap();
+            Ok(HttpResponse::Ok().json(json! {
+                {
+                    "message": "successfully logged in"
+                }
+            }))
+        }
+        _ => {
+            login_guard.record(&conn, &user, ipaddr, LoginResult::Failed)?;
+            let delay = login_guard.failure_delay(&conn, &user)?;
+            // give the connection back to the pool while waiting
+            drop(conn);
+            actix_web::rt::time::sleep(delay).await;
+            Err(AppError::AuthErr(LOGIN_ERR_MSG.to_string()))
+        }
+    }
+}
+
+/// The second step of `login` for users with TOTP enabled.
+async fn login_totp(
+    request: HttpRequest,
+    session: Session,
+    db: web::Data<Pool>,
+    totp_data: web::Json<LoginTotpRequest>,
+    client_ip: client_ip::ClientIp,
+    login_guard: web::Data<loginguard::LoginGuard>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    let pending_user_id = session.get::<uuid::Uuid>(TOTP_PENDING_USER_KEY)?;
+    let pending_since = session.get::<i64>(TOTP_PENDING_SINCE_KEY)?.unwrap_or(0);
+    let pending_user_id = match pending_user_id {
+        Some(v) if chrono::Utc::now().timestamp() - pending_since < TOTP_PENDING_SECS => v,
+        _ => return Err(AppError::AuthErr(LOGIN_ERR_MSG.to_string())),
+    };
+
+    let user_found = users::dsl::users
+        .find(pending_user_id)
+        .first::<models::User>(&conn)
+        .optional()?
+        .filter(|u| !user::is_user_disabled(u))
+        .ok_or_else(|| AppError::AuthErr(LOGIN_ERR_MSG.to_string()))?;
+
+    let ipaddr = client_ip.network();
+    if let Err(e) = login_guard.chk_not_locked(&conn, &user_found.username, ipaddr) {
+        login_guard.record(&conn, &user_found.username, ipaddr, LoginResult::LockedOut)?;
+        return Err(e);
+    }
+
+    if !totp::verify_second_factor(&conn, &user_found, &totp_data.code)? {
+        login_guard.record(&conn, &user_found.username, ipaddr, LoginResult::Failed)?;
+        let delay = login_guard.failure_delay(&conn, &user_found.username)?;
+        drop(conn);
+        actix_web::rt::time::sleep(delay).await;
         return Err(AppError::AuthErr(LOGIN_ERR_MSG.to_string()));
     }
+
+    session.remove(TOTP_PENDING_USER_KEY);
+    session.remove(TOTP_PENDING_SINCE_KEY);
+    login_guard.record(&conn, &user_found.username, ipaddr, LoginResult::Succeeded)?;
+    Identity::login(&request.extensions(), user_found.username).unwrap();
+    Ok(HttpResponse::Ok().json(json! {
+        {
+            "message": "successfully logged in"
+        }
+    }))
 }
 
 async fn logout(user: Identity) -> impl Responder {
@@ -114,6 +225,31 @@ async fn main() -> std::io::Result<()> {
                 }
             }
 
+            if let Ok(site_name) = env::var("SITE_NAME") {
+                if let Err(e) = site::name_default_site(&db_conn, &site_name) {
+                    return std::io::Result::Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("failed to name the default site: {}", e),
+                    ));
+                }
+            }
+
+            let renderer = markdown::make_markdown_renderer();
+            match markdown::render_missing_html(&db_conn, &renderer) {
+                Ok(n) => {
+                    println!("rendered {} existing comments", n);
+                }
+                Err(e) => {
+                    return std::io::Result::Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("failed to render existing comments: {}", e),
+                    ));
+                }
+            }
+
+            return Ok(());
+        } else if cmd == "genkey" {
+            println!("{}", sessionkey::generate_key_string());
             return Ok(());
         } else {
             panic!("unknown command: {}", cmd);
@@ -125,10 +261,49 @@ async fn main() -> std::io::Result<()> {
     let pool = establish_main_db_pool();
     println!("Connected to database");
 
-    let secret_key = Key::generate();
+    let spam_filter = web::Data::new(spamfilter::make_spam_filter_pipeline());
+
+    let session_keys = web::Data::new(sessionkey::SessionKeys::from_env());
     let redis_store = establish_session_db().await;
     println!("Connected to session db");
 
+    let rate_limiter = web::Data::new(ratelimit::make_rate_limiter().await);
+    let trusted_proxies = web::Data::new(client_ip::TrustedProxies::from_env());
+    let markdown_renderer = web::Data::new(markdown::make_markdown_renderer());
+    let auto_register = web::Data::new(page::AutoRegisterPrefixes::from_env());
+    let login_guard = web::Data::new(loginguard::LoginGuard::from_env());
+    let oidc_provider = web::Data::new(commenter::OidcProvider::from_env());
+    let reaction_kinds = web::Data::new(reaction::ReactionKinds::from_env());
+
+    let site_origins = web::Data::new(site::SiteOrigins::default());
+    site_origins
+        .reload(&pool.get().expect("failed to connect to database"))
+        .expect("failed to load the allowed origins of sites");
+    {
+        // sites added or changed with masacarri_cli are picked up here
+        let site_origins = site_origins.clone();
+        let pool = pool.clone();
+        actix_web::rt::spawn(async move {
+            let mut interval =
+                actix_web::rt::time::interval(Duration::from_secs(SITE_ORIGINS_REFRESH_SECS));
+            loop {
+                interval.tick().await;
+                let site_origins = site_origins.clone();
+                let pool = pool.clone();
+                let _ = web::block(move || {
+                    let res = pool
+                        .get()
+                        .map_err(AppError::from)
+                        .and_then(|conn| site_origins.reload(&conn));
+                    if let Err(e) = res {
+                        log::warn!("failed to reload the allowed origins of sites: {}", e);
+                    }
+                })
+                .await;
+            }
+        });
+    }
+
     let host_self = env::var("HOST").expect("HOST must be set");
     let port_self = env::var("PORT")
         .expect("PORT must be set")
@@ -139,15 +314,26 @@ async fn main() -> std::io::Result<()> {
         let mode = env::var("MODE").unwrap_or("production".to_string());
 
         let cors = {
-            let front_origin =
-                env::var("FRONT_ORIGIN").unwrap_or("http://127.0.0.1:5173".to_string());
+            let cors_origins = site_origins.clone();
 
-            Cors::default()
-                .allowed_origin(front_origin.as_str())
-                .allowed_methods(vec!["GET", "POST", "PATCH", "DELETE"])
+            // front ends of the sites are allowed through the sites table
+            let cors = Cors::default().allowed_origin_fn(move |origin, _| {
+                origin
+                    .to_str()
+                    .map(|origin| cors_origins.contains(origin))
+                    .unwrap_or(false)
+            });
+            let cors = match env::var("FRONT_ORIGIN") {
+                Ok(front_origin) => cors.allowed_origin(front_origin.as_str()),
+                Err(_) if mode == "development" => cors.allowed_origin("http://127.0.0.1:5173"),
+                Err(_) => cors,
+            };
+
+            cors.allowed_methods(vec!["GET", "POST", "PUT", "PATCH", "DELETE"])
                 .allowed_headers(vec![
                     http::header::AUTHORIZATION,
                     http::header::CONTENT_TYPE,
+                    http::header::HeaderName::from_static("x-commenter-token"),
                 ])
                 .supports_credentials()
                 .max_age(3600)
@@ -157,23 +343,107 @@ async fn main() -> std::io::Result<()> {
             .login_deadline(Some(Duration::new(3600 * 3, 0)))
             .build();
 
-        let session_middleware = SessionMiddleware::new(redis_store.clone(), secret_key.clone());
+        let session_middleware =
+            SessionMiddleware::builder(redis_store.clone(), session_keys.current.clone())
+                .cookie_name(sessionkey::SESSION_COOKIE_NAME.to_string())
+                .build();
+        let session_keys = session_keys.clone();
 
         let bgtask_manager = bgtask::make_bgtask_manager();
 
         App::new()
             .app_data(web::Data::new(bgtask_manager))
             .app_data(web::Data::new(pool.clone()))
+            .app_data(spam_filter.clone())
+            .app_data(rate_limiter.clone())
+            .app_data(trusted_proxies.clone())
+            .app_data(markdown_renderer.clone())
+            .app_data(auto_register.clone())
+            .app_data(login_guard.clone())
+            .app_data(oidc_provider.clone())
+            .app_data(reaction_kinds.clone())
+            .app_data(site_origins.clone())
             .wrap(Logger::default())
             .wrap(identity_middleware)
             .wrap(session_middleware)
-            .wrap(Condition::new(mode == "development", cors))
+            .wrap_fn(move |mut req, srv| {
+                let upgraded = session_keys.upgrade_request(&mut req);
+                let res = srv.call(req);
+                async move {
+                    let mut res = res.await?;
+                    if let Some(cookie) = upgraded {
+                        let already_set = res
+                            .response()
+                            .cookies()
+                            .any(|c| c.name() == sessionkey::SESSION_COOKIE_NAME);
+                        if !already_set {
+                            res.response_mut().add_cookie(&cookie)?;
+                        }
+                    }
+                    Ok(res)
+                }
+            })
+            .wrap(cors)
             .route("/api/login", web::post().to(login))
+            .route("/api/login/totp", web::post().to(login_totp))
             .route("/api/logout", web::get().to(logout))
+            .route(
+                "/api/login_attempts",
+                web::get().to(loginguard::get_login_attempts),
+            )
+            .route("/api/audit_logs", web::get().to(audit::get_audit_logs))
+            .route(
+                "/api/commenter/provider",
+                web::get().to(commenter::get_commenter_provider),
+            )
+            .route(
+                "/api/commenter/login",
+                web::get().to(commenter::begin_commenter_login),
+            )
+            .route(
+                "/api/commenter/callback",
+                web::get().to(commenter::finish_commenter_login),
+            )
+            .route(
+                "/api/commenter/me",
+                web::get().to(commenter::get_current_commenter),
+            )
+            .route(
+                "/api/commenter/logout",
+                web::post().to(commenter::end_commenter_session),
+            )
+            .route("/api/tokens", web::get().to(apitoken::get_api_tokens))
+            .route("/api/tokens", web::post().to(apitoken::create_api_token))
+            .route(
+                "/api/tokens/{token}",
+                web::delete().to(apitoken::delete_api_token),
+            )
+            .route("/api/users", web::get().to(get_users))
+            .route("/api/users", web::post().to(invite_user))
+            .route("/api/users/me", web::patch().to(modify_own_profile))
+            .route("/api/users/me/password", web::put().to(change_own_password))
+            .route(
+                "/api/users/me/totp",
+                web::post().to(totp::begin_totp_enrollment),
+            )
+            .route(
+                "/api/users/me/totp/confirm",
+                web::post().to(totp::confirm_totp_enrollment),
+            )
+            .route("/api/users/me/totp", web::delete().to(totp::disable_totp))
+            .route("/api/users/{user}", web::patch().to(modify_user))
+            .route("/api/users/{user}", web::delete().to(delete_user))
+            .route("/api/sites", web::get().to(get_sites))
+            .route("/api/sites/{site}", web::patch().to(modify_site))
             .route("/api/pages", web::get().to(get_page_all))
             .route("/api/pages", web::post().to(add_page))
+            .route("/api/pages/lookup", web::get().to(lookup_page))
             .route("/api/pages/{page}", web::patch().to(modify_page))
             .route("/api/pages/{page}", web::delete().to(delete_page))
+            .route("/api/bans", web::get().to(get_bans))
+            .route("/api/bans", web::post().to(add_ban))
+            .route("/api/bans/{ban}", web::patch().to(modify_ban))
+            .route("/api/bans/{ban}", web::delete().to(delete_ban))
             .route("/api/pages/{page}/comments", web::get().to(get_comments))
             .route("/api/pages/{page}/comments", web::post().to(add_comment))
             .route(
@@ -184,10 +454,54 @@ async fn main() -> std::io::Result<()> {
                 "/api/pages/{page}/comments/{comment}",
                 web::patch().to(mark_comment),
             )
+            .route(
+                "/api/pages/{page}/comments/{comment}",
+                web::put().to(edit_comment),
+            )
+            .route(
+                "/api/pages/{page}/comments/{comment}",
+                web::delete().to(delete_comment),
+            )
+            .route(
+                "/api/pages/{page}/comments/{comment}/ban",
+                web::post().to(ban_comment_author),
+            )
+            .route(
+                "/api/pages/{page}/comments/{comment}/revisions",
+                web::get().to(get_comment_revisions),
+            )
+            .route(
+                "/api/pages/{page}/comments/{comment}/revisions/{revision}/restore",
+                web::post().to(restore_comment_revision),
+            )
+            .route(
+                "/api/pages/{page}/comments/{comment}/reactions/{kind}",
+                web::put().to(reaction::add_reaction),
+            )
+            .route(
+                "/api/pages/{page}/comments/{comment}/reactions/{kind}",
+                web::delete().to(reaction::delete_reaction),
+            )
+            .route("/api/reactions", web::get().to(reaction::get_reaction_kinds))
+            .route(
+                "/api/pages/{page}/comments/move",
+                web::post().to(move_page_comments),
+            )
             .route(
                 "/api/pages/{page}/comments_count",
                 web::get().to(get_comment_count),
             )
+            .route(
+                "/api/moderation/comments",
+                web::get().to(get_moderation_queue),
+            )
+            .route(
+                "/api/moderation/comments",
+                web::post().to(moderate_comments),
+            )
+            .route("/embed", web::get().to(get_embed_by_url))
+            .route("/embed/{page}", web::get().to(get_embed))
+            .route("/embed/{page}/comments", web::post().to(add_embed_comment))
             .service(
                 actix_files::Files::new("/", "../masacarri-front/dist")
                     .index_file("index.html")
@@ -0,0 +1,154 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+
+use crate::db::MainDbConnection;
+use crate::schema::comments::dsl::*;
+use diesel::prelude::*;
+use pulldown_cmark::{html, Event, Options, Parser, Tag};
+
+/// Syntax which can be switched off with `MARKDOWN_DISABLE`.
+pub struct MarkdownSyntax {
+    pub headings: bool,
+    pub images: bool,
+    pub links: bool,
+    pub code_blocks: bool,
+    pub tables: bool,
+    pub strikethrough: bool,
+}
+
+impl MarkdownSyntax {
+    pub fn from_env() -> Self {
+        let disabled: Vec<String> = env::var("MARKDOWN_DISABLE")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        for item in disabled.iter() {
+            match item.as_str() {
+                "headings" | "images" | "links" | "code_blocks" | "tables" | "strikethrough" => {}
+                x => panic!("invalid MARKDOWN_DISABLE value: {}", x),
+            }
+        }
+
+        let enabled = |name: &str| !disabled.iter().any(|d| d == name);
+
+        MarkdownSyntax {
+            headings: enabled("headings"),
+            images: enabled("images"),
+            links: enabled("links"),
+            code_blocks: enabled("code_blocks"),
+            tables: enabled("tables"),
+            strikethrough: enabled("strikethrough"),
+        }
+    }
+}
+
+pub struct MarkdownRenderer {
+    syntax: MarkdownSyntax,
+    sanitizer: ammonia::Builder<'static>,
+}
+
+impl MarkdownRenderer {
+    pub fn new(syntax: MarkdownSyntax) -> Self {
+        let mut tags: HashSet<&'static str> = [
+            "p", "br", "em", "strong", "code", "blockquote", "ul", "ol", "li", "hr",
+        ]
+        .into_iter()
+        .collect();
+        let mut tag_attributes: HashMap<&'static str, HashSet<&'static str>> = HashMap::new();
+        tag_attributes.insert("ol", ["start"].into_iter().collect());
+
+        if syntax.headings {
+            tags.extend(["h1", "h2", "h3", "h4", "h5", "h6"]);
+        }
+        if syntax.images {
+            tags.insert("img");
+            tag_attributes.insert("img", ["src", "alt", "title"].into_iter().collect());
+        }
+        if syntax.links {
+            tags.insert("a");
+            tag_attributes.insert("a", ["href", "title"].into_iter().collect());
+        }
+        if syntax.code_blocks {
+            tags.insert("pre");
+        }
+        if syntax.tables {
+            tags.extend(["table", "thead", "tbody", "tr", "th", "td"]);
+        }
+        if syntax.strikethrough {
+            tags.insert("del");
+        }
+
+        let mut sanitizer = ammonia::Builder::default();
+        sanitizer
+            .tags(tags)
+            .tag_attributes(tag_attributes)
+            .generic_attributes(HashSet::new())
+            .url_schemes(["http", "https", "mailto"].into_iter().collect())
+            .link_rel(Some("nofollow noopener noreferrer ugc"));
+
+        MarkdownRenderer { syntax, sanitizer }
+    }
+
+    pub fn render(&self, text: &str) -> String {
+        let mut options = Options::empty();
+        if self.syntax.tables {
+            options.insert(Options::ENABLE_TABLES);
+        }
+        if self.syntax.strikethrough {
+            options.insert(Options::ENABLE_STRIKETHROUGH);
+        }
+
+        let events = Parser::new_ext(text, options).filter_map(|event| match event {
+            // comments are written in a textarea, so a newline means a newline
+            Event::SoftBreak => Some(Event::HardBreak),
+            // raw HTML is shown as typed instead of being silently dropped
+            Event::Html(s) => Some(Event::Text(s)),
+            Event::Start(Tag::Heading(..)) if !self.syntax.headings => {
+                Some(Event::Start(Tag::Paragraph))
+            }
+            Event::End(Tag::Heading(..)) if !self.syntax.headings => {
+                Some(Event::End(Tag::Paragraph))
+            }
+            Event::Start(Tag::CodeBlock(_)) if !self.syntax.code_blocks => {
+                Some(Event::Start(Tag::Paragraph))
+            }
+            Event::End(Tag::CodeBlock(_)) if !self.syntax.code_blocks => {
+                Some(Event::End(Tag::Paragraph))
+            }
+            Event::Start(Tag::Image(..)) | Event::End(Tag::Image(..)) if !self.syntax.images => {
+                None
+            }
+            Event::Start(Tag::Link(..)) | Event::End(Tag::Link(..)) if !self.syntax.links => None,
+            e => Some(e),
+        });
+
+        let mut unsafe_html = String::new();
+        html::push_html(&mut unsafe_html, events);
+
+        self.sanitizer.clean(&unsafe_html).to_string()
+    }
+}
+
+pub fn make_markdown_renderer() -> MarkdownRenderer {
+    MarkdownRenderer::new(MarkdownSyntax::from_env())
+}
+
+/// Fills `content_html` of comments posted before rendering was introduced.
+pub fn render_missing_html(conn: &MainDbConnection, renderer: &MarkdownRenderer) -> QueryResult<usize> {
+    let targets = comments
+        .select((id, content))
+        .filter(content_html.eq(""))
+        .filter(content.ne(""))
+        .load::<(uuid::Uuid, String)>(conn)?;
+
+    for (tgt_id, tgt_content) in targets.iter() {
+        diesel::update(comments.find(tgt_id))
+            .set(content_html.eq(renderer.render(tgt_content)))
+            .execute(conn)?;
+    }
+
+    Ok(targets.len())
+}
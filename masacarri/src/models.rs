@@ -1,6 +1,11 @@
+use std::io::Write;
+
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::Pg;
+use diesel::serialize::{self, IsNull, Output, ToSql};
 use diesel::Queryable;
 use diesel::QueryableByName;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use diesel::sql_types::*;
 
 use crate::schema::comments;
@@ -11,6 +16,60 @@ pub struct User {
     pub username: String,
     pub password_hash: String,
     pub flags: i32,
+    pub role: Role,
+    pub totp_secret: Option<String>,
+    pub totp_pending_secret: Option<String>,
+    pub totp_last_step: Option<i64>,
+    pub display_name: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsExpression, FromSqlRow, Serialize, Deserialize)]
+#[sql_type = "Text"]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Owner,
+    Admin,
+    Moderator,
+    ReadOnly,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Owner => "owner",
+            Role::Admin => "admin",
+            Role::Moderator => "moderator",
+            Role::ReadOnly => "readonly",
+        }
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "owner" => Ok(Role::Owner),
+            "admin" => Ok(Role::Admin),
+            "moderator" => Ok(Role::Moderator),
+            "readonly" => Ok(Role::ReadOnly),
+            x => Err(format!("unknown role: {}", x)),
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for Role {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for Role {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let s = std::str::from_utf8(not_none!(bytes))?;
+        Ok(s.parse::<Role>()?)
+    }
 }
 
 #[derive(Queryable, Serialize)]
@@ -19,6 +78,60 @@ pub struct Page {
     pub title: String,
     pub page_url: String,
     pub published: bool,
+    pub premoderated: bool,
+    pub site_id: uuid::Uuid,
+    pub allow_anonymous: bool,
+    /// Archived pages are kept with their comments but are private and take no new comments.
+    pub archived: bool,
+}
+
+/// Pages and users created before sites existed belong to this one.
+pub const DEFAULT_SITE_ID: uuid::Uuid = uuid::Uuid::nil();
+
+#[derive(Queryable, Serialize)]
+pub struct Site {
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub allowed_origins: Vec<String>,
+    pub mail_from: Option<String>,
+    pub premoderated: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsExpression, FromSqlRow, Serialize, Deserialize)]
+#[sql_type = "Text"]
+#[serde(rename_all = "lowercase")]
+pub enum CommentStatus {
+    Pending,
+    Approved,
+    Rejected,
+}
+
+impl CommentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CommentStatus::Pending => "pending",
+            CommentStatus::Approved => "approved",
+            CommentStatus::Rejected => "rejected",
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for CommentStatus {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for CommentStatus {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"pending" => Ok(CommentStatus::Pending),
+            b"approved" => Ok(CommentStatus::Approved),
+            b"rejected" => Ok(CommentStatus::Rejected),
+            _ => Err("unrecognized comment status".into()),
+        }
+    }
 }
 
 #[derive(Queryable, Clone)]
@@ -34,6 +147,12 @@ pub struct Comment {
     pub delete_key: String,
     pub flags: i32,
     pub created_time: chrono::DateTime<chrono::Utc>,
+    pub status: CommentStatus,
+    pub content_html: String,
+    pub commenter_id: Option<uuid::Uuid>,
+    pub avatar_url: Option<String>,
+    pub author_user_id: Option<uuid::Uuid>,
+    pub edited_time: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 
@@ -51,8 +170,124 @@ pub struct CommentWithReplies {
     pub delete_key: String,
     pub flags: i32,
     pub created_time: chrono::DateTime<chrono::Utc>,
+    pub status: CommentStatus,
+    pub content_html: String,
+    pub commenter_id: Option<uuid::Uuid>,
+    pub avatar_url: Option<String>,
+    pub author_user_id: Option<uuid::Uuid>,
+    pub edited_time: Option<chrono::DateTime<chrono::Utc>>,
     #[sql_type = "BigInt"]
     pub count_replies: i64,
+    #[sql_type = "Jsonb"]
+    pub reactions: serde_json::Value,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct Ban {
+    pub id: uuid::Uuid,
+    pub ip_range: Option<ipnetwork::IpNetwork>,
+    pub mail_addr: Option<String>,
+    pub display_name_pattern: Option<String>,
+    pub reason: Option<String>,
+    pub created_time: chrono::DateTime<chrono::Utc>,
+    pub expires_time: Option<chrono::DateTime<chrono::Utc>>,
+    pub site_id: uuid::Uuid,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct SpamReport {
+    pub id: uuid::Uuid,
+    pub comment_id: uuid::Uuid,
+    pub filter_name: String,
+    pub score: f64,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsExpression, FromSqlRow, Serialize, Deserialize)]
+#[sql_type = "Text"]
+#[serde(rename_all = "lowercase")]
+pub enum LoginResult {
+    Succeeded,
+    Failed,
+    LockedOut,
+}
+
+impl LoginResult {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginResult::Succeeded => "succeeded",
+            LoginResult::Failed => "failed",
+            LoginResult::LockedOut => "lockedout",
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for LoginResult {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for LoginResult {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"succeeded" => Ok(LoginResult::Succeeded),
+            b"failed" => Ok(LoginResult::Failed),
+            b"lockedout" => Ok(LoginResult::LockedOut),
+            _ => Err("unrecognized login result".into()),
+        }
+    }
+}
+
+#[derive(Queryable, Serialize)]
+pub struct LoginAttempt {
+    pub id: uuid::Uuid,
+    pub username: String,
+    pub ip_addr: ipnetwork::IpNetwork,
+    pub result: LoginResult,
+    pub created_time: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct Commenter {
+    pub id: uuid::Uuid,
+    pub display_name: String,
+    pub avatar_url: Option<String>,
+}
+
+/// A version of a comment that an edit replaced. `created_time` is when it was posted.
+#[derive(Queryable, Serialize)]
+pub struct CommentRevision {
+    pub id: uuid::Uuid,
+    pub comment_id: uuid::Uuid,
+    pub content: String,
+    pub content_html: String,
+    pub created_time: chrono::DateTime<chrono::Utc>,
+    pub replaced_time: chrono::DateTime<chrono::Utc>,
+    pub replaced_by_user_id: Option<uuid::Uuid>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct AuditLog {
+    pub id: uuid::Uuid,
+    pub user_id: Option<uuid::Uuid>,
+    pub username: String,
+    pub action: String,
+    pub target_id: uuid::Uuid,
+    pub detail: serde_json::Value,
+    pub created_time: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct ApiToken {
+    pub id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub created_time: chrono::DateTime<chrono::Utc>,
+    pub expires_time: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_used_time: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Queryable, QueryableByName)]
@@ -60,3 +295,15 @@ pub struct CountResult {
     #[sql_type = "BigInt"]
     pub count: i64,
 }
+
+#[derive(QueryableByName)]
+pub struct IdResult {
+    #[sql_type = "Uuid"]
+    pub id: uuid::Uuid,
+}
+
+#[derive(QueryableByName)]
+pub struct ReactionCountsResult {
+    #[sql_type = "Jsonb"]
+    pub reactions: serde_json::Value,
+}
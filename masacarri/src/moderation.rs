@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::env;
+
+use crate::auth::{managed_site_ids, AuthUser, Permission};
+use crate::bgtask::BgTaskManager;
+use crate::db::Pool;
+use crate::error::{AppError, AppResult};
+use crate::mail::MailNotifyTask;
+use crate::models::{Comment, CommentStatus, SpamReport};
+use crate::schema::{comments, pages, spam_reports};
+use crate::spamfilter::SpamFilterPipeline;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use static_assertions::const_assert;
+
+const DEFAULT_ITEMS_PER_PAGE: u32 = 50;
+const DEFAULT_PAGE_INDEX: u32 = 1;
+const MAX_ITEMS_PER_PAGE: u32 = 256;
+
+const_assert!(DEFAULT_ITEMS_PER_PAGE <= MAX_ITEMS_PER_PAGE);
+const_assert!(DEFAULT_PAGE_INDEX == 1);
+
+pub fn is_premoderation_enabled() -> bool {
+    env::var("PREMODERATION")
+        .map(|v| v == "true")
+        .unwrap_or(false)
+}
+
+#[derive(Deserialize)]
+pub struct GetModerationQueueQuery {
+    status: Option<CommentStatus>,
+    site: Option<uuid::Uuid>,
+    page: Option<uuid::Uuid>,
+    ip_addr: Option<ipnetwork::IpNetwork>,
+    num: Option<u32>,
+    index: Option<u32>,
+}
+
+#[derive(Queryable, Serialize)]
+pub struct ModerationQueueItem {
+    id: uuid::Uuid,
+    page_id: uuid::Uuid,
+    page_title: String,
+    reply_to: Option<uuid::Uuid>,
+    ip_addr: ipnetwork::IpNetwork,
+    display_name: String,
+    site_url: Option<String>,
+    mail_addr: Option<String>,
+    content: String,
+    created_time: DateTime<Utc>,
+    status: CommentStatus,
+}
+
+#[derive(Serialize)]
+pub struct ModerationQueueEntry {
+    #[serde(flatten)]
+    comment: ModerationQueueItem,
+    spam_reports: Vec<SpamReport>,
+}
+
+#[derive(Deserialize)]
+pub struct ModerateCommentsRequest {
+    comments: Vec<uuid::Uuid>,
+    status: CommentStatus,
+}
+
+pub async fn get_moderation_queue(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    query_param: web::Query<GetModerationQueueQuery>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    let items_per_page = query_param.num.unwrap_or(DEFAULT_ITEMS_PER_PAGE);
+    let items_page_index = query_param.index.unwrap_or(DEFAULT_PAGE_INDEX);
+
+    if items_page_index < 1 {
+        return Err(AppError::PublishableErr("invalid page index".to_string()));
+    }
+
+    let items_page_index = items_page_index - 1;
+
+    if items_per_page == 0 || items_per_page > MAX_ITEMS_PER_PAGE {
+        return Err(AppError::PublishableErr(format!(
+            "Items per page is limited up to {}.",
+            MAX_ITEMS_PER_PAGE
+        )));
+    }
+
+    user.require(Permission::ViewPrivate)?;
+
+    let mut site_ids = managed_site_ids(&conn, &user)?;
+    if let Some(tgt_site_id) = query_param.site {
+        site_ids.retain(|v| *v == tgt_site_id);
+    }
+
+    let mut query = comments::table
+        .inner_join(pages::table)
+        .select((
+            comments::id,
+            comments::page_id,
+            pages::title,
+            comments::reply_to,
+            comments::ip_addr,
+            comments::display_name,
+            comments::site_url,
+            comments::mail_addr,
+            comments::content,
+            comments::created_time,
+            comments::status,
+        ))
+        .filter(comments::status.eq(query_param.status.unwrap_or(CommentStatus::Pending)))
+        .filter(pages::site_id.eq_any(site_ids))
+        .into_boxed();
+
+    if let Some(tgt_page_id) = query_param.page {
+        query = query.filter(comments::page_id.eq(tgt_page_id));
+    }
+    if let Some(tgt_ip_addr) = query_param.ip_addr {
+        query = query.filter(comments::ip_addr.eq(tgt_ip_addr));
+    }
+
+    let result = query
+        .order(comments::created_time)
+        .offset((items_per_page * items_page_index).into())
+        .limit(items_per_page.into())
+        .load::<ModerationQueueItem>(&conn)?;
+
+    let comment_ids: Vec<uuid::Uuid> = result.iter().map(|item| item.id).collect();
+    let mut reports_by_comment: HashMap<uuid::Uuid, Vec<SpamReport>> = HashMap::new();
+    for report in spam_reports::table
+        .filter(spam_reports::comment_id.eq_any(&comment_ids))
+        .order(spam_reports::score.desc())
+        .load::<SpamReport>(&conn)?
+    {
+        reports_by_comment
+            .entry(report.comment_id)
+            .or_default()
+            .push(report);
+    }
+
+    let entries: Vec<ModerationQueueEntry> = result
+        .into_iter()
+        .map(|item| ModerationQueueEntry {
+            spam_reports: reports_by_comment.remove(&item.id).unwrap_or_default(),
+            comment: item,
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+pub async fn moderate_comments(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    moderation: web::Json<ModerateCommentsRequest>,
+    bgtask_manager: web::Data<BgTaskManager>,
+    spam_filter: web::Data<SpamFilterPipeline>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    let ModerateCommentsRequest {
+        comments: r_comments,
+        status: r_status,
+    } = moderation.into_inner();
+
+    user.require(Permission::ModerateComments)?;
+    let site_ids = managed_site_ids(&conn, &user)?;
+
+    // comments on other sites are silently left alone
+    let updated = diesel::update(
+        comments::table
+            .filter(comments::id.eq_any(&r_comments))
+            .filter(
+                comments::page_id.eq_any(
+                    pages::table
+                        .select(pages::id)
+                        .filter(pages::site_id.eq_any(site_ids)),
+                ),
+            )
+            .filter(comments::status.ne(r_status)),
+    )
+    .set(comments::status.eq(r_status))
+    .get_results::<Comment>(&conn)?;
+
+    spam_filter.invalidate();
+
+    // replies held for moderation notify only once they become visible
+    if r_status == CommentStatus::Approved {
+        for comment_new in updated.iter() {
+            if let Some(id_replyto) = comment_new.reply_to {
+                bgtask_manager.do_send(MailNotifyTask {
+                    id_replyto,
+                    pool: db.get_ref().clone(),
+                    comment_new: comment_new.clone(),
+                });
+            }
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "updated": updated.len(),
+    })))
+}
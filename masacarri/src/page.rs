@@ -1,18 +1,31 @@
-use crate::db::Pool;
+use crate::audit;
+use crate::auth::{
+    can_manage_page, chk_manage_page, managed_site_ids, resolve_site_id, AuthUser, Permission,
+};
+use crate::comment::move_comments;
+use crate::db::{MainDbConnection, Pool};
 use crate::error::{AppError, AppResult};
 use crate::models::Page;
+use crate::site::site_for_origin;
 use crate::schema::pages;
 use crate::schema::pages::dsl::*;
-use actix_identity::Identity;
-use actix_web::{web, HttpResponse, Responder};
+use crate::schema::comments;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use diesel::prelude::*;
 use serde::Deserialize;
+use serde_json::json;
+use std::env;
 
 #[derive(Deserialize)]
 pub struct NewPageRequest {
     title: String,
     page_url: String,
     published: bool,
+    #[serde(default)]
+    premoderated: bool,
+    site_id: Option<uuid::Uuid>,
+    allow_anonymous: Option<bool>,
 }
 
 #[derive(Insertable)]
@@ -22,6 +35,14 @@ pub struct NewPage {
     title: String,
     page_url: String,
     published: bool,
+    premoderated: bool,
+    site_id: uuid::Uuid,
+    allow_anonymous: bool,
+}
+
+#[derive(Deserialize)]
+pub struct GetPagesRequestQuery {
+    site: Option<uuid::Uuid>,
 }
 
 #[derive(Deserialize)]
@@ -29,6 +50,10 @@ pub struct ModifyPageRequest {
     title: String,
     page_url: String,
     published: bool,
+    premoderated: Option<bool>,
+    site_id: Option<uuid::Uuid>,
+    allow_anonymous: Option<bool>,
+    archived: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -41,16 +66,232 @@ pub struct DeletePageRequestPath {
     page: uuid::Uuid,
 }
 
-pub async fn get_page_all(_: Identity, db: web::Data<Pool>) -> AppResult<impl Responder> {
+/// What happens to the comments of a deleted page.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletePageMode {
+    /// Only pages without comments are deleted.
+    #[default]
+    Refuse,
+    Cascade,
+    /// The page is kept, unpublished, with its comments.
+    Archive,
+    /// The comments are moved to `move_to` before the page is deleted.
+    Move,
+}
+
+#[derive(Deserialize)]
+pub struct DeletePageRequestQuery {
+    #[serde(default)]
+    mode: DeletePageMode,
+    move_to: Option<uuid::Uuid>,
+}
+
+#[derive(Deserialize)]
+pub struct LookupPageRequestQuery {
+    url: String,
+    title: Option<String>,
+}
+
+/// URL prefixes whose unknown pages are registered on first lookup.
+#[derive(Clone, Default)]
+pub struct AutoRegisterPrefixes(Vec<String>);
+
+impl AutoRegisterPrefixes {
+    pub fn from_env() -> Self {
+        let prefixes = env::var("AUTO_REGISTER_PREFIXES")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                url::Url::parse(s)
+                    .unwrap_or_else(|_| panic!("AUTO_REGISTER_PREFIXES has an invalid entry: {}", s))
+                    .to_string()
+            })
+            .collect();
+        AutoRegisterPrefixes(prefixes)
+    }
+
+    /// `origin` is the origin the request came from, if the browser told us.
+    fn allows(&self, normalized_url: &str, origin: Option<&str>) -> bool {
+        if !self.0.iter().any(|prefix| normalized_url.starts_with(prefix.as_str())) {
+            return false;
+        }
+        match (origin, url::Url::parse(normalized_url)) {
+            (Some(origin), Ok(parsed)) => parsed.origin().ascii_serialization() == origin,
+            (None, Ok(_)) => true,
+            (_, Err(_)) => false,
+        }
+    }
+}
+
+/// Drops the query string, the fragment and trailing slashes so that
+/// every link to an article resolves to the same page.
+/// Returns `None` unless `raw` is an http(s) URL.
+pub fn normalize_page_url(raw: &str) -> Option<String> {
+    let mut parsed = url::Url::parse(raw.trim()).ok()?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return None;
+    }
+    parsed.set_query(None);
+    parsed.set_fragment(None);
+    let path = parsed.path().to_string();
+    if path.len() > 1 && path.ends_with('/') {
+        parsed.set_path(path.trim_end_matches('/'));
+    }
+    Some(parsed.to_string())
+}
+
+/// The origin of the document which sent the request: `Origin`, or the
+/// origin part of `Referer` for plain navigations such as iframes.
+pub fn request_origin(req: &HttpRequest) -> Option<String> {
+    let headers = req.headers();
+    if let Some(origin) = headers.get(header::ORIGIN).and_then(|v| v.to_str().ok()) {
+        if origin != "null" {
+            return Some(origin.to_string());
+        }
+    }
+    headers
+        .get(header::REFERER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| url::Url::parse(v).ok())
+        .map(|v| v.origin().ascii_serialization())
+}
+
+/// Comments moved to an archived page could no longer be read or replied to.
+/// Locks the page so that it is not archived before the move is committed.
+pub fn chk_move_target(conn: &MainDbConnection, tgt_page_id: uuid::Uuid) -> AppResult<()> {
+    let is_archived: bool = pages
+        .select(archived)
+        .filter(id.eq(tgt_page_id))
+        .for_update()
+        .first(conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFoundErr("page not found".to_string()))?;
+
+    if is_archived {
+        return Err(AppError::PublishableErr(
+            "The page to move the comments to is archived.".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the page and whether it has just been registered.
+pub fn find_or_register_page(
+    conn: &MainDbConnection,
+    raw_url: &str,
+    r_title: Option<String>,
+    auto_register: &AutoRegisterPrefixes,
+    origin: Option<&str>,
+    user: &Option<AuthUser>,
+) -> AppResult<(Page, bool)> {
+    let normalized = normalize_page_url(raw_url)
+        .ok_or_else(|| AppError::PublishableErr("invalid page url".to_string()))?;
+
+    // pages registered by hand keep the url as it was entered
+    let found = pages
+        .filter(page_url.eq_any(vec![normalized.as_str(), raw_url.trim()]))
+        .order(page_url.ne(normalized.as_str()))
+        .first::<Page>(conn)
+        .optional()?;
+
+    if let Some(page) = found {
+        if !page.published && !can_manage_page(conn, user, page.id, Permission::ViewPrivate)? {
+            return Err(AppError::NotFoundErr("page not found".to_string()));
+        }
+        return Ok((page, false));
+    }
+
+    if normalized.len() > 512 || !auto_register.allows(&normalized, origin) {
+        return Err(AppError::NotFoundErr("page not found".to_string()));
+    }
+
+    let r_title = r_title
+        .map(|t| t.trim().chars().take(1024).collect::<String>())
+        .filter(|t| !t.is_empty())
+        .unwrap_or_else(|| normalized.clone());
+
+    let url_origin = url::Url::parse(&normalized)
+        .map(|v| v.origin().ascii_serialization())
+        .map_err(|_| AppError::UnspecifiedErr)?;
+    let site = site_for_origin(conn, &url_origin)?;
+
+    // a concurrent lookup of the same article may win the race
+    let created = diesel::insert_into(pages)
+        .values(NewPage {
+            id: uuid::Uuid::new_v4(),
+            title: r_title,
+            page_url: normalized.clone(),
+            published: true,
+            premoderated: false,
+            site_id: site.id,
+            allow_anonymous: true,
+        })
+        .on_conflict_do_nothing()
+        .execute(conn)?
+        > 0;
+
+    let page = pages
+        .filter(page_url.eq(normalized.as_str()))
+        .first::<Page>(conn)?;
+
+    Ok((page, created))
+}
+
+pub async fn lookup_page(
+    request: HttpRequest,
+    db: web::Data<Pool>,
+    query_param: web::Query<LookupPageRequestQuery>,
+    auto_register: web::Data<AutoRegisterPrefixes>,
+    user: Option<AuthUser>,
+) -> AppResult<impl Responder> {
     let conn = db.get()?;
 
-    let result = pages.load::<Page>(&conn)?;
+    let LookupPageRequestQuery {
+        url: r_url,
+        title: r_title,
+    } = query_param.into_inner();
+
+    let (page, created) = find_or_register_page(
+        &conn,
+        &r_url,
+        r_title,
+        &auto_register,
+        request_origin(&request).as_deref(),
+        &user,
+    )?;
+
+    if created {
+        Ok(HttpResponse::Created().json(page))
+    } else {
+        Ok(HttpResponse::Ok().json(page))
+    }
+}
+
+pub async fn get_page_all(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    query_param: web::Query<GetPagesRequestQuery>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    user.require(Permission::ViewPrivate)?;
+
+    let mut site_ids = managed_site_ids(&conn, &user)?;
+    if let Some(tgt_site_id) = query_param.site {
+        site_ids.retain(|v| *v == tgt_site_id);
+    }
+
+    let result = pages.filter(site_id.eq_any(site_ids)).load::<Page>(&conn)?;
 
     Ok(HttpResponse::Ok().json(result))
 }
 
 pub async fn add_page(
-    _: Identity,
+    user: AuthUser,
     db: web::Data<Pool>,
     new_page: web::Json<NewPageRequest>,
 ) -> AppResult<impl Responder> {
@@ -60,8 +301,14 @@ pub async fn add_page(
         title: r_title,
         page_url: r_page_url,
         published: r_published,
+        premoderated: r_premoderated,
+        site_id: r_site_id,
+        allow_anonymous: r_allow_anonymous,
     } = new_page.into_inner();
 
+    user.require(Permission::ManagePages)?;
+    let r_site_id = resolve_site_id(&conn, &user, r_site_id)?;
+
     let new_id = uuid::Uuid::new_v4();
 
     let res = diesel::insert_into(pages)
@@ -70,6 +317,9 @@ pub async fn add_page(
             title: r_title,
             page_url: r_page_url,
             published: r_published,
+            premoderated: r_premoderated,
+            site_id: r_site_id,
+            allow_anonymous: r_allow_anonymous.unwrap_or(true),
         })
         .execute(&conn);
     if let Err(_) = res {
@@ -86,31 +336,148 @@ pub async fn add_page(
     Ok(HttpResponse::Created().json(result.first().ok_or(AppError::UnspecifiedErr)?))
 }
 
+/// Archived pages are unpublished as well, whichever way they are archived.
+fn set_page_archived(
+    conn: &MainDbConnection,
+    tgt_page_id: uuid::Uuid,
+    r_archived: bool,
+) -> AppResult<()> {
+    if r_archived {
+        diesel::update(pages.filter(id.eq(tgt_page_id)))
+            .set((archived.eq(true), published.eq(false)))
+            .execute(conn)?;
+    } else {
+        diesel::update(pages.filter(id.eq(tgt_page_id)))
+            .set(archived.eq(false))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
 pub async fn modify_page(
-    _: Identity,
+    user: AuthUser,
     db: web::Data<Pool>,
     path_param: web::Path<ModifyPageRequestPath>,
     updated_page: web::Json<ModifyPageRequest>,
 ) -> AppResult<impl Responder> {
     let conn = db.get()?;
 
-    diesel::update(pages.filter(id.eq(path_param.page)))
-        .set((
-            title.eq(&updated_page.title),
-            page_url.eq(&updated_page.page_url),
-            published.eq(&updated_page.published),
-        ))
-        .execute(&conn)?;
+    chk_manage_page(&conn, &user, path_param.page, Permission::ManagePages)?;
+
+    let r_site_id = match updated_page.site_id {
+        Some(r_site_id) => Some(resolve_site_id(&conn, &user, Some(r_site_id))?),
+        None => None,
+    };
+
+    conn.transaction(|| {
+        diesel::update(pages.filter(id.eq(path_param.page)))
+            .set((
+                title.eq(&updated_page.title),
+                page_url.eq(&updated_page.page_url),
+                published.eq(&updated_page.published),
+            ))
+            .execute(&conn)?;
+        if let Some(r_premoderated) = updated_page.premoderated {
+            diesel::update(pages.filter(id.eq(path_param.page)))
+                .set(premoderated.eq(r_premoderated))
+                .execute(&conn)?;
+        }
+        if let Some(r_allow_anonymous) = updated_page.allow_anonymous {
+            diesel::update(pages.filter(id.eq(path_param.page)))
+                .set(allow_anonymous.eq(r_allow_anonymous))
+                .execute(&conn)?;
+        }
+        if let Some(r_site_id) = r_site_id {
+            diesel::update(pages.filter(id.eq(path_param.page)))
+                .set(site_id.eq(r_site_id))
+                .execute(&conn)?;
+        }
+        if let Some(r_archived) = updated_page.archived {
+            set_page_archived(&conn, path_param.page, r_archived)?;
+        }
+        Ok::<_, AppError>(())
+    })?;
+
     Ok(HttpResponse::NoContent())
 }
 
 pub async fn delete_page(
-    _: Identity,
+    user: AuthUser,
     db: web::Data<Pool>,
     path_param: web::Path<DeletePageRequestPath>,
+    query_param: web::Query<DeletePageRequestQuery>,
 ) -> AppResult<impl Responder> {
     let conn = db.get()?;
 
-    diesel::delete(pages.filter(id.eq(path_param.page))).execute(&conn)?;
+    chk_manage_page(&conn, &user, path_param.page, Permission::ManagePages)?;
+
+    let tgt_page_id = path_param.page;
+    let DeletePageRequestQuery {
+        mode: r_mode,
+        move_to: r_move_to,
+    } = query_param.into_inner();
+
+    let r_move_to = match (r_mode, r_move_to) {
+        (DeletePageMode::Move, Some(r_move_to)) if r_move_to != tgt_page_id => {
+            chk_manage_page(&conn, &user, r_move_to, Permission::ManagePages)?;
+            Some(r_move_to)
+        }
+        (DeletePageMode::Move, _) => {
+            return Err(AppError::PublishableErr(
+                "Choose another page to move the comments to.".to_string(),
+            ));
+        }
+        _ => None,
+    };
+
+    conn.transaction(|| {
+        let count_comments: i64 = comments::table
+            .filter(comments::page_id.eq(tgt_page_id))
+            .count()
+            .get_result(&conn)?;
+
+        match r_mode {
+            DeletePageMode::Refuse if count_comments > 0 => {
+                return Err(AppError::PublishableErr(format!(
+                    "This page has {} comments. Delete them with the page, archive the page or move them to another page.",
+                    count_comments
+                )));
+            }
+            DeletePageMode::Refuse => {}
+            DeletePageMode::Cascade => {
+                diesel::delete(comments::table.filter(comments::page_id.eq(tgt_page_id)))
+                    .execute(&conn)?;
+            }
+            DeletePageMode::Archive => {
+                set_page_archived(&conn, tgt_page_id, true)?;
+            }
+            DeletePageMode::Move => {
+                let r_move_to = r_move_to.ok_or(AppError::UnspecifiedErr)?;
+                chk_move_target(&conn, r_move_to)?;
+                move_comments(&conn, tgt_page_id, r_move_to, None)?;
+            }
+        }
+
+        if r_mode != DeletePageMode::Archive {
+            diesel::delete(pages.filter(id.eq(tgt_page_id))).execute(&conn)?;
+        }
+
+        audit::record(
+            &conn,
+            &user,
+            if r_mode == DeletePageMode::Archive {
+                "page.archive"
+            } else {
+                "page.delete"
+            },
+            tgt_page_id,
+            json!({
+                "comments": count_comments,
+                "deleted_comments": r_mode == DeletePageMode::Cascade,
+                "moved_to": r_move_to,
+            }),
+        )
+    })?;
+
     Ok(HttpResponse::NoContent())
 }
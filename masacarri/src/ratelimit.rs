@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{AppError, AppResult};
+use crate::utils::env_or;
+use redis::aio::ConnectionManager;
+
+const MEMORY_BUCKETS_PRUNE_SIZE: usize = 10000;
+
+// KEYS[1]: bucket key, ARGV: capacity, refill per second, now (seconds)
+// returns seconds to wait, 0 if a token was taken
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local now = tonumber(ARGV[3])
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(bucket[1]) or capacity
+local ts = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - ts) * rate)
+local retry = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+else
+    retry = math.ceil((1 - tokens) / rate)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', tostring(now))
+redis.call('EXPIRE', KEYS[1], math.ceil(capacity / rate) + 1)
+return retry
+"#;
+
+#[derive(Clone, Copy)]
+pub struct RateLimitRule {
+    pub capacity: f64,
+    pub refill_per_sec: f64,
+}
+
+struct MemoryBucket {
+    tokens: f64,
+    ts: f64,
+}
+
+pub struct RateLimiter {
+    redis: Option<ConnectionManager>,
+    memory: Mutex<HashMap<String, MemoryBucket>>,
+    per_ip: Option<RateLimitRule>,
+    per_page: Option<RateLimitRule>,
+    per_ip_reactions: Option<RateLimitRule>,
+}
+
+fn now_secs() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0)
+}
+
+/// IPv6 clients usually own a whole /64, so they share one bucket.
+fn ip_bucket_key(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(v4) => format!("ip:{}", v4),
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            format!(
+                "ip6:{:x}:{:x}:{:x}:{:x}::/64",
+                segments[0], segments[1], segments[2], segments[3]
+            )
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(
+        redis: Option<ConnectionManager>,
+        per_ip: Option<RateLimitRule>,
+        per_page: Option<RateLimitRule>,
+        per_ip_reactions: Option<RateLimitRule>,
+    ) -> Self {
+        RateLimiter {
+            redis,
+            memory: Mutex::new(HashMap::new()),
+            per_ip,
+            per_page,
+            per_ip_reactions,
+        }
+    }
+
+    /// Takes one token from every bucket the comment counts against.
+    /// The page bucket is left alone once the address is throttled, so that
+    /// a single client cannot drain it for everyone else.
+    pub async fn check_comment(&self, ip: IpAddr, page: uuid::Uuid) -> AppResult<()> {
+        if let Some(rule) = self.per_ip {
+            let retry_after = self.take(&ip_bucket_key(ip), rule).await;
+            if retry_after > 0 {
+                return Err(AppError::RateLimitErr(retry_after));
+            }
+        }
+        if let Some(rule) = self.per_page {
+            let retry_after = self.take(&format!("page:{}", page), rule).await;
+            if retry_after > 0 {
+                return Err(AppError::RateLimitErr(retry_after));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reactions have a bucket of their own, so that reading and reacting
+    /// does not use up the comments an address may post.
+    pub async fn check_reaction(&self, ip: IpAddr) -> AppResult<()> {
+        if let Some(rule) = self.per_ip_reactions {
+            let retry_after = self
+                .take(&format!("reaction:{}", ip_bucket_key(ip)), rule)
+                .await;
+            if retry_after > 0 {
+                return Err(AppError::RateLimitErr(retry_after));
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn take(&self, key: &str, rule: RateLimitRule) -> u64 {
+        let now = now_secs();
+
+        if let Some(redis) = &self.redis {
+            let res = redis::cmd("EVAL")
+                .arg(TOKEN_BUCKET_SCRIPT)
+                .arg(1)
+                .arg(format!("masacarri:ratelimit:{}", key))
+                .arg(rule.capacity)
+                .arg(rule.refill_per_sec)
+                .arg(now)
+                .query_async::<_, u64>(&mut redis.clone())
+                .await;
+            match res {
+                Ok(retry_after) => return retry_after,
+                Err(e) => log::warn!("rate limit falls back to memory: {}", e),
+            }
+        }
+
+        self.take_memory(key, rule, now)
+    }
+
+    fn take_memory(&self, key: &str, rule: RateLimitRule, now: f64) -> u64 {
+        let mut buckets = self.memory.lock().unwrap();
+
+        if buckets.len() > MEMORY_BUCKETS_PRUNE_SIZE {
+            let full_after = rule.capacity / rule.refill_per_sec;
+            buckets.retain(|_, b| now - b.ts < full_after);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(MemoryBucket {
+            tokens: rule.capacity,
+            ts: now,
+        });
+
+        bucket.tokens =
+            rule.capacity.min(bucket.tokens + (now - bucket.ts).max(0.0) * rule.refill_per_sec);
+        bucket.ts = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            0
+        } else {
+            ((1.0 - bucket.tokens) / rule.refill_per_sec).ceil() as u64
+        }
+    }
+}
+
+fn load_rule(burst_key: &str, per_minute_key: &str, burst: u32, per_minute: u32) -> Option<RateLimitRule> {
+    let burst: u32 = env_or(burst_key, burst);
+    let per_minute: u32 = env_or(per_minute_key, per_minute);
+
+    if burst == 0 || per_minute == 0 {
+        return None;
+    }
+
+    Some(RateLimitRule {
+        capacity: burst.into(),
+        refill_per_sec: f64::from(per_minute) / 60.0,
+    })
+}
+
+pub async fn make_rate_limiter() -> RateLimiter {
+    let per_ip = load_rule("RATE_LIMIT_IP_BURST", "RATE_LIMIT_IP_PER_MINUTE", 5, 2);
+    let per_page = load_rule("RATE_LIMIT_PAGE_BURST", "RATE_LIMIT_PAGE_PER_MINUTE", 30, 10);
+    let per_ip_reactions = load_rule(
+        "RATE_LIMIT_REACTION_BURST",
+        "RATE_LIMIT_REACTION_PER_MINUTE",
+        30,
+        20,
+    );
+
+    let session_database_url = env::var("SESSION_DATABASE_URL")
+        .expect("SESSION_DATABASE_URL must be set");
+    let redis = match redis::Client::open(session_database_url) {
+        Ok(client) => ConnectionManager::new(client).await.ok(),
+        Err(_) => None,
+    };
+    if redis.is_none() {
+        log::warn!("rate limit counters are kept in memory");
+    }
+
+    RateLimiter::new(redis, per_ip, per_page, per_ip_reactions)
+}
@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::env;
+
+use crate::client_ip::ClientIp;
+use crate::comment::{chk_page_public, MARK_AS_DELETED_FRAG_BIT, MARK_AS_SPAM_FRAG_BIT};
+use crate::commenter::AuthCommenter;
+use crate::db::{MainDbPooledConnection, Pool};
+use crate::error::{AppError, AppResult};
+use crate::models::{CommentStatus, ReactionCountsResult};
+use crate::ratelimit::RateLimiter;
+use crate::schema::{comments, reactions};
+use crate::utils::sha256_hex;
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use diesel::pg::types::sql_types;
+use diesel::{prelude::*, sql_query};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Deserialize;
+use serde_json::json;
+
+const VOTER_COOKIE_NAME: &str = "masacarri_voter";
+const VOTER_COOKIE_LENGTH: usize = 40;
+const VOTER_COOKIE_DAYS: i64 = 365;
+const DEFAULT_REACTION_KINDS: &str = "like,thanks,laugh,surprised";
+const MAX_REACTION_KIND_LENGTH: usize = 32;
+
+/// The reactions readers can choose from, in the order they are shown.
+#[derive(Clone, Default)]
+pub struct ReactionKinds(Vec<String>);
+
+impl ReactionKinds {
+    pub fn from_env() -> Self {
+        let kinds = env::var("REACTION_KINDS")
+            .unwrap_or_else(|_| DEFAULT_REACTION_KINDS.to_string())
+            .split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let is_valid = s.len() <= MAX_REACTION_KIND_LENGTH
+                    && s
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+                if !is_valid {
+                    panic!("REACTION_KINDS has an invalid entry: {}", s);
+                }
+                s.to_string()
+            })
+            .collect();
+        ReactionKinds(kinds)
+    }
+
+    fn contains(&self, kind: &str) -> bool {
+        self.0.iter().any(|k| k == kind)
+    }
+}
+
+#[derive(Insertable)]
+#[table_name = "reactions"]
+pub struct NewReaction {
+    id: uuid::Uuid,
+    comment_id: uuid::Uuid,
+    kind: String,
+    voter: String,
+    created_time: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct ReactionRequestPath {
+    page: uuid::Uuid,
+    comment: uuid::Uuid,
+    kind: String,
+}
+
+/// Who is reacting: the signed-in commenter, or else the browser's voter cookie together with
+/// its address. The cookie is handed out on the first reaction and only stored hashed.
+struct Voter {
+    fingerprint: String,
+    new_cookie: Option<Cookie<'static>>,
+}
+
+fn identify_voter(
+    req: &HttpRequest,
+    client_ip: &ClientIp,
+    commenter: &Option<AuthCommenter>,
+) -> Voter {
+    if let Some(c) = commenter {
+        return Voter {
+            fingerprint: format!("commenter:{}", c.commenter.id),
+            new_cookie: None,
+        };
+    }
+
+    let (value, new_cookie) = match req.cookie(VOTER_COOKIE_NAME) {
+        Some(cookie) => (cookie.value().to_string(), None),
+        None => {
+            let value = Alphanumeric.sample_string(&mut rand::thread_rng(), VOTER_COOKIE_LENGTH);
+            // the comment form is framed by other sites, which only send cookies made for that
+            let cookie = Cookie::build(VOTER_COOKIE_NAME, value.clone())
+                .path("/")
+                .http_only(true)
+                .secure(true)
+                .same_site(SameSite::None)
+                .max_age(time::Duration::days(VOTER_COOKIE_DAYS))
+                .finish();
+            (value, Some(cookie))
+        }
+    };
+
+    Voter {
+        fingerprint: format!(
+            "cookie:{}",
+            sha256_hex(&format!("{}/{}", client_ip.0, value))
+        ),
+        new_cookie,
+    }
+}
+
+/// Reactions are only taken on comments readers can see.
+fn chk_comment_reactable(
+    conn: &MainDbPooledConnection,
+    path_param: &ReactionRequestPath,
+    kinds: &ReactionKinds,
+) -> AppResult<()> {
+    if !kinds.contains(&path_param.kind) {
+        return Err(AppError::PublishableErr("Unknown reaction.".to_string()));
+    }
+
+    chk_page_public(conn, path_param.page)?;
+
+    let (tgt_status, tgt_flags) = comments::table
+        .select((comments::status, comments::flags))
+        .filter(comments::id.eq(path_param.comment))
+        .filter(comments::page_id.eq(path_param.page))
+        .first::<(CommentStatus, i32)>(conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFoundErr("comment not found".to_string()))?;
+
+    if tgt_status != CommentStatus::Approved
+        || tgt_flags & (MARK_AS_SPAM_FRAG_BIT | MARK_AS_DELETED_FRAG_BIT) != 0
+    {
+        return Err(AppError::PublishableErr(
+            "You cannot react to this comment.".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn reaction_response(
+    conn: &MainDbPooledConnection,
+    tgt_comment_id: uuid::Uuid,
+    voter: Voter,
+) -> AppResult<HttpResponse> {
+    let counts = sql_query(
+        r#"
+            select coalesce(jsonb_object_agg(kind, n), '{}'::jsonb) as reactions
+            from (
+                select kind, count(*) as n from reactions
+                where comment_id = $1
+                group by kind
+            ) as counted
+        "#,
+    )
+    .bind::<sql_types::Uuid, _>(tgt_comment_id)
+    .get_result::<ReactionCountsResult>(conn)?
+    .reactions;
+    let counts: BTreeMap<String, i64> = serde_json::from_value(counts).unwrap_or_default();
+
+    let reacted = reactions::table
+        .select(reactions::kind)
+        .filter(reactions::comment_id.eq(tgt_comment_id))
+        .filter(reactions::voter.eq(&voter.fingerprint))
+        .order(reactions::kind)
+        .load::<String>(conn)?;
+
+    let mut response = HttpResponse::Ok();
+    if let Some(cookie) = voter.new_cookie {
+        response.cookie(cookie);
+    }
+    Ok(response.json(json!({
+        "reactions": counts,
+        "reacted": reacted,
+    })))
+}
+
+pub async fn get_reaction_kinds(kinds: web::Data<ReactionKinds>) -> AppResult<impl Responder> {
+    Ok(HttpResponse::Ok().json(json!({
+        "kinds": kinds.0,
+    })))
+}
+
+/// Reacting twice with the same kind counts once.
+pub async fn add_reaction(
+    req: HttpRequest,
+    client_ip: ClientIp,
+    db: web::Data<Pool>,
+    path_param: web::Path<ReactionRequestPath>,
+    kinds: web::Data<ReactionKinds>,
+    rate_limiter: web::Data<RateLimiter>,
+    commenter: Option<AuthCommenter>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    chk_comment_reactable(&conn, &path_param, &kinds)?;
+
+    rate_limiter.check_reaction(client_ip.0).await?;
+
+    let voter = identify_voter(&req, &client_ip, &commenter);
+
+    diesel::insert_into(reactions::table)
+        .values(NewReaction {
+            id: uuid::Uuid::new_v4(),
+            comment_id: path_param.comment,
+            kind: path_param.kind.clone(),
+            voter: voter.fingerprint.clone(),
+            created_time: Utc::now(),
+        })
+        .on_conflict((reactions::comment_id, reactions::kind, reactions::voter))
+        .do_nothing()
+        .execute(&conn)?;
+
+    reaction_response(&conn, path_param.comment, voter)
+}
+
+pub async fn delete_reaction(
+    req: HttpRequest,
+    client_ip: ClientIp,
+    db: web::Data<Pool>,
+    path_param: web::Path<ReactionRequestPath>,
+    kinds: web::Data<ReactionKinds>,
+    rate_limiter: web::Data<RateLimiter>,
+    commenter: Option<AuthCommenter>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    chk_comment_reactable(&conn, &path_param, &kinds)?;
+
+    rate_limiter.check_reaction(client_ip.0).await?;
+
+    let voter = identify_voter(&req, &client_ip, &commenter);
+
+    diesel::delete(
+        reactions::table
+            .filter(reactions::comment_id.eq(path_param.comment))
+            .filter(reactions::kind.eq(&path_param.kind))
+            .filter(reactions::voter.eq(&voter.fingerprint)),
+    )
+    .execute(&conn)?;
+
+    reaction_response(&conn, path_param.comment, voter)
+}
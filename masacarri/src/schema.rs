@@ -1,3 +1,53 @@
+table! {
+    api_tokens (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        name -> Varchar,
+        token_hash -> Varchar,
+        scopes -> Array<Text>,
+        created_time -> Timestamptz,
+        expires_time -> Nullable<Timestamptz>,
+        last_used_time -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    audit_logs (id) {
+        id -> Uuid,
+        user_id -> Nullable<Uuid>,
+        username -> Varchar,
+        action -> Varchar,
+        target_id -> Uuid,
+        detail -> Jsonb,
+        created_time -> Timestamptz,
+    }
+}
+
+table! {
+    bans (id) {
+        id -> Uuid,
+        ip_range -> Nullable<Inet>,
+        mail_addr -> Nullable<Varchar>,
+        display_name_pattern -> Nullable<Varchar>,
+        reason -> Nullable<Varchar>,
+        created_time -> Timestamptz,
+        expires_time -> Nullable<Timestamptz>,
+        site_id -> Uuid,
+    }
+}
+
+table! {
+    comment_revisions (id) {
+        id -> Uuid,
+        comment_id -> Uuid,
+        content -> Varchar,
+        content_html -> Text,
+        created_time -> Timestamptz,
+        replaced_time -> Timestamptz,
+        replaced_by_user_id -> Nullable<Uuid>,
+    }
+}
+
 table! {
     comments (id) {
         id -> Uuid,
@@ -11,6 +61,44 @@ table! {
         delete_key -> Varchar,
         flags -> Int4,
         created_time -> Timestamptz,
+        status -> Varchar,
+        content_html -> Text,
+        commenter_id -> Nullable<Uuid>,
+        avatar_url -> Nullable<Varchar>,
+        author_user_id -> Nullable<Uuid>,
+        edited_time -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    commenter_sessions (id) {
+        id -> Uuid,
+        commenter_id -> Uuid,
+        token_hash -> Varchar,
+        created_time -> Timestamptz,
+        expires_time -> Timestamptz,
+    }
+}
+
+table! {
+    commenters (id) {
+        id -> Uuid,
+        issuer -> Varchar,
+        subject -> Varchar,
+        display_name -> Varchar,
+        avatar_url -> Nullable<Varchar>,
+        created_time -> Timestamptz,
+        last_login_time -> Timestamptz,
+    }
+}
+
+table! {
+    login_attempts (id) {
+        id -> Uuid,
+        username -> Varchar,
+        ip_addr -> Inet,
+        result -> Varchar,
+        created_time -> Timestamptz,
     }
 }
 
@@ -20,6 +108,56 @@ table! {
         title -> Varchar,
         page_url -> Varchar,
         published -> Bool,
+        premoderated -> Bool,
+        site_id -> Uuid,
+        allow_anonymous -> Bool,
+        archived -> Bool,
+    }
+}
+
+table! {
+    reactions (id) {
+        id -> Uuid,
+        comment_id -> Uuid,
+        kind -> Varchar,
+        voter -> Varchar,
+        created_time -> Timestamptz,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        id -> Uuid,
+        user_id -> Uuid,
+        code_hash -> Varchar,
+        used_time -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    site_users (site_id, user_id) {
+        site_id -> Uuid,
+        user_id -> Uuid,
+    }
+}
+
+table! {
+    sites (id) {
+        id -> Uuid,
+        name -> Varchar,
+        allowed_origins -> Array<Text>,
+        mail_from -> Nullable<Varchar>,
+        premoderated -> Bool,
+    }
+}
+
+table! {
+    spam_reports (id) {
+        id -> Uuid,
+        comment_id -> Uuid,
+        filter_name -> Varchar,
+        score -> Float8,
+        reason -> Varchar,
     }
 }
 
@@ -29,13 +167,44 @@ table! {
         username -> Varchar,
         password_hash -> Varchar,
         flags -> Int4,
+        role -> Varchar,
+        totp_secret -> Nullable<Varchar>,
+        totp_pending_secret -> Nullable<Varchar>,
+        totp_last_step -> Nullable<Int8>,
+        display_name -> Nullable<Varchar>,
     }
 }
 
+joinable!(api_tokens -> users (user_id));
+joinable!(audit_logs -> users (user_id));
+joinable!(bans -> sites (site_id));
+joinable!(comment_revisions -> comments (comment_id));
+joinable!(comment_revisions -> users (replaced_by_user_id));
+joinable!(commenter_sessions -> commenters (commenter_id));
+joinable!(comments -> commenters (commenter_id));
 joinable!(comments -> pages (page_id));
+joinable!(comments -> users (author_user_id));
+joinable!(pages -> sites (site_id));
+joinable!(reactions -> comments (comment_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(site_users -> sites (site_id));
+joinable!(site_users -> users (user_id));
+joinable!(spam_reports -> comments (comment_id));
 
 allow_tables_to_appear_in_same_query!(
+    api_tokens,
+    audit_logs,
+    bans,
+    comment_revisions,
+    commenter_sessions,
+    commenters,
     comments,
+    login_attempts,
     pages,
+    reactions,
+    recovery_codes,
+    site_users,
+    sites,
+    spam_reports,
     users,
 );
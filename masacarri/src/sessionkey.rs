@@ -0,0 +1,131 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use actix_web::cookie::{Cookie, CookieJar, Key, SameSite};
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{HeaderValue, COOKIE};
+
+pub const SESSION_COOKIE_NAME: &str = "id";
+const DEFAULT_SESSION_KEY_FILE: &str = "session.key";
+const SESSION_KEY_BYTES: usize = 64;
+
+/// The key session cookies are encrypted with, and the one it replaced during a rotation.
+pub struct SessionKeys {
+    pub current: Key,
+    previous: Option<Key>,
+}
+
+fn decode_key(name: &str, encoded: &str) -> Key {
+    let decoded = base64::decode(encoded.trim())
+        .unwrap_or_else(|_| panic!("{} must be base64", name));
+    if decoded.len() < SESSION_KEY_BYTES {
+        panic!("{} must be at least {} bytes", name, SESSION_KEY_BYTES);
+    }
+    Key::from(&decoded)
+}
+
+pub fn generate_key_string() -> String {
+    base64::encode(Key::generate().master())
+}
+
+/// Reads the key file, or creates it with a new key on first boot.
+fn load_or_create_key_file(path: &Path) -> Key {
+    if path.exists() {
+        let encoded = fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e));
+        return decode_key(&path.display().to_string(), &encoded);
+    }
+
+    let encoded = generate_key_string();
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .unwrap_or_else(|e| panic!("failed to create {}: {}", path.display(), e));
+    writeln!(file, "{}", encoded)
+        .unwrap_or_else(|e| panic!("failed to write {}: {}", path.display(), e));
+    println!("Generated a new session key in {}", path.display());
+
+    decode_key(&path.display().to_string(), &encoded)
+}
+
+impl SessionKeys {
+    pub fn from_env() -> Self {
+        let current = match env::var("SESSION_KEY") {
+            Ok(v) if !v.is_empty() => decode_key("SESSION_KEY", &v),
+            _ => {
+                let path = env::var("SESSION_KEY_FILE")
+                    .ok()
+                    .filter(|v| !v.is_empty())
+                    .unwrap_or_else(|| DEFAULT_SESSION_KEY_FILE.to_string());
+                load_or_create_key_file(Path::new(&path))
+            }
+        };
+
+        let previous = match env::var("SESSION_KEY_PREVIOUS") {
+            Ok(v) if !v.is_empty() => Some(decode_key("SESSION_KEY_PREVIOUS", &v)),
+            _ => None,
+        };
+
+        SessionKeys { current, previous }
+    }
+
+    /// Re-encrypts a session cookie made with the previous key so that the session middleware
+    /// accepts it. The returned cookie should be sent back to replace the old one.
+    pub fn upgrade_request(&self, req: &mut ServiceRequest) -> Option<Cookie<'static>> {
+        let previous = self.previous.as_ref()?;
+        let header = req.headers().get(COOKIE)?.to_str().ok()?.to_string();
+
+        let mut upgraded = None;
+        let rewritten: Vec<String> = header
+            .split(';')
+            .map(|pair| {
+                let pair = pair.trim();
+                match pair.split_once('=') {
+                    Some((name, value)) if name == SESSION_COOKIE_NAME => {
+                        match self.reencrypt(previous, value) {
+                            Some(cookie) => {
+                                let pair = format!("{}={}", SESSION_COOKIE_NAME, cookie.value());
+                                upgraded = Some(cookie);
+                                pair
+                            }
+                            None => pair.to_string(),
+                        }
+                    }
+                    _ => pair.to_string(),
+                }
+            })
+            .collect();
+
+        let upgraded = upgraded?;
+        let rewritten = HeaderValue::from_str(&rewritten.join("; ")).ok()?;
+        req.headers_mut().insert(COOKIE, rewritten);
+
+        Some(upgraded)
+    }
+
+    fn reencrypt(&self, previous: &Key, value: &str) -> Option<Cookie<'static>> {
+        let mut jar = CookieJar::new();
+        jar.add_original(Cookie::new(SESSION_COOKIE_NAME, value.to_string()));
+        if jar.private(&self.current).get(SESSION_COOKIE_NAME).is_some() {
+            return None;
+        }
+        let decrypted = jar.private(previous).get(SESSION_COOKIE_NAME)?;
+
+        // the same attributes as the session middleware gives its cookie
+        let mut jar = CookieJar::new();
+        jar.private_mut(&self.current).add(
+            Cookie::build(SESSION_COOKIE_NAME, decrypted.value().to_string())
+                .path("/")
+                .secure(true)
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .finish(),
+        );
+        jar.get(SESSION_COOKIE_NAME).cloned()
+    }
+}
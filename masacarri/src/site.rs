@@ -0,0 +1,147 @@
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+use crate::auth::{chk_manage_site, managed_site_ids, AuthUser, Permission};
+use crate::db::{MainDbConnection, Pool};
+use crate::error::{AppError, AppResult};
+use crate::models::{Site, DEFAULT_SITE_ID};
+use crate::schema::sites;
+use crate::schema::sites::dsl::*;
+use crate::utils::empty_to_none;
+use actix_web::{web, HttpResponse, Responder};
+use diesel::prelude::*;
+use serde::Deserialize;
+
+#[derive(Insertable)]
+#[table_name = "sites"]
+pub struct NewSite {
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub allowed_origins: Vec<String>,
+    pub mail_from: Option<String>,
+    pub premoderated: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ModifySiteRequestPath {
+    site: uuid::Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct ModifySiteRequest {
+    name: String,
+    allowed_origins: Vec<String>,
+    mail_from: Option<String>,
+    premoderated: bool,
+}
+
+/// Reduces `https://example.com/some/path` to `https://example.com`.
+pub fn normalize_origin(raw: &str) -> Option<String> {
+    let parsed = url::Url::parse(raw.trim()).ok()?;
+    if parsed.scheme() != "http" && parsed.scheme() != "https" {
+        return None;
+    }
+    Some(parsed.origin().ascii_serialization())
+}
+
+/// The site whose allowed origins include `origin`, or the default site.
+pub fn site_for_origin(conn: &MainDbConnection, origin: &str) -> AppResult<Site> {
+    let found = sites
+        .filter(allowed_origins.contains(vec![origin.to_string()]))
+        .order(id)
+        .first::<Site>(conn)
+        .optional()?;
+
+    match found {
+        Some(site) => Ok(site),
+        None => Ok(sites.find(DEFAULT_SITE_ID).first::<Site>(conn)?),
+    }
+}
+
+/// The allowed origins of every site, kept in memory for the CORS check of each request.
+#[derive(Default)]
+pub struct SiteOrigins(RwLock<HashSet<String>>);
+
+impl SiteOrigins {
+    pub fn reload(&self, conn: &MainDbConnection) -> AppResult<()> {
+        let found = sites.select(allowed_origins).load::<Vec<String>>(conn)?;
+        *self.0.write().unwrap() = found.into_iter().flatten().collect();
+        Ok(())
+    }
+
+    pub fn contains(&self, origin: &str) -> bool {
+        self.0.read().unwrap().contains(origin)
+    }
+}
+
+/// Names the default site after `SITE_NAME` unless it has been renamed already.
+pub fn name_default_site(conn: &MainDbConnection, site_name: &str) -> QueryResult<usize> {
+    diesel::update(sites.find(DEFAULT_SITE_ID).filter(name.eq("default")))
+        .set(name.eq(site_name))
+        .execute(conn)
+}
+
+pub async fn get_sites(user: AuthUser, db: web::Data<Pool>) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    user.require(Permission::ViewPrivate)?;
+
+    let site_ids = managed_site_ids(&conn, &user)?;
+    let result = sites
+        .filter(id.eq_any(site_ids))
+        .order(name)
+        .load::<Site>(&conn)?;
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+pub async fn modify_site(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    path_param: web::Path<ModifySiteRequestPath>,
+    updated_site: web::Json<ModifySiteRequest>,
+    site_origins: web::Data<SiteOrigins>,
+) -> AppResult<impl Responder> {
+    let conn = db.get()?;
+
+    user.require(Permission::ManageSite)?;
+    chk_manage_site(&conn, &user, path_param.site)?;
+
+    let ModifySiteRequest {
+        name: r_name,
+        allowed_origins: r_allowed_origins,
+        mail_from: r_mail_from,
+        premoderated: r_premoderated,
+    } = updated_site.into_inner();
+
+    if r_name.trim().is_empty() {
+        return Err(AppError::PublishableErr("Site name is required.".to_string()));
+    }
+
+    let r_allowed_origins = r_allowed_origins
+        .iter()
+        .map(|origin| {
+            normalize_origin(origin).ok_or_else(|| {
+                AppError::PublishableErr(format!("'{}' is not a valid origin.", origin))
+            })
+        })
+        .collect::<AppResult<Vec<String>>>()?;
+
+    let res = diesel::update(sites.find(path_param.site))
+        .set((
+            name.eq(r_name.trim()),
+            allowed_origins.eq(r_allowed_origins),
+            mail_from.eq(empty_to_none(r_mail_from)),
+            premoderated.eq(r_premoderated),
+        ))
+        .execute(&conn);
+    if res.is_err() {
+        return Err(AppError::PublishableErr(
+            "Failed to update the site. The name may already be used.".to_owned(),
+        ));
+    }
+
+    site_origins.reload(&conn)?;
+
+    Ok(HttpResponse::NoContent())
+}
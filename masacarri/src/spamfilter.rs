@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::RwLock;
+
+use crate::comment::{MARK_AS_DELETED_FRAG_BIT, MARK_AS_SPAM_FRAG_BIT};
+use crate::db::MainDbPooledConnection;
+use crate::error::AppResult;
+use crate::models::{CommentStatus, CountResult};
+use crate::schema::comments;
+use crate::utils::env_or;
+use chrono::{Duration, Utc};
+use diesel::dsl::sql;
+use diesel::prelude::*;
+use diesel::sql_types::{Bool, Inet, Timestamptz};
+use diesel::sql_query;
+use serde::Serialize;
+
+/// Everything a filter may look at before a comment is inserted.
+pub struct SpamCheckTarget<'a> {
+    pub ip_addr: ipnetwork::IpNetwork,
+    pub display_name: &'a str,
+    pub site_url: Option<&'a str>,
+    pub mail_addr: Option<&'a str>,
+    pub content: &'a str,
+    pub honeypot: Option<&'a str>,
+}
+
+/// A score of 1.0 is meant as "suspicious enough to hold on its own".
+pub struct FilterResult {
+    pub score: f64,
+    pub reason: Option<String>,
+}
+
+impl FilterResult {
+    pub fn clean() -> Self {
+        FilterResult {
+            score: 0.0,
+            reason: None,
+        }
+    }
+}
+
+pub trait SpamFilter: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn evaluate(
+        &self,
+        conn: &MainDbPooledConnection,
+        target: &SpamCheckTarget,
+    ) -> AppResult<FilterResult>;
+
+    /// Called whenever admins change which comments are spam.
+    fn invalidate(&self) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpamVerdict {
+    Accept,
+    Hold,
+    Reject,
+}
+
+#[derive(Serialize)]
+pub struct SpamReason {
+    pub filter_name: String,
+    pub score: f64,
+    pub reason: String,
+}
+
+pub struct SpamCheckReport {
+    pub verdict: SpamVerdict,
+    pub reasons: Vec<SpamReason>,
+}
+
+pub struct SpamFilterPipeline {
+    filters: Vec<Box<dyn SpamFilter>>,
+    hold_threshold: f64,
+    reject_threshold: f64,
+}
+
+impl SpamFilterPipeline {
+    pub fn new(hold_threshold: f64, reject_threshold: f64) -> Self {
+        SpamFilterPipeline {
+            filters: Vec::new(),
+            hold_threshold,
+            reject_threshold,
+        }
+    }
+
+    pub fn with_filter<F: SpamFilter + 'static>(mut self, filter: F) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    pub fn check(
+        &self,
+        conn: &MainDbPooledConnection,
+        target: &SpamCheckTarget,
+    ) -> AppResult<SpamCheckReport> {
+        let mut score = 0.0;
+        let mut reasons = Vec::new();
+
+        for filter in self.filters.iter() {
+            let result = filter.evaluate(conn, target)?;
+            score += result.score;
+            if let Some(reason) = result.reason {
+                reasons.push(SpamReason {
+                    filter_name: filter.name().to_string(),
+                    score: result.score,
+                    reason,
+                });
+            }
+        }
+
+        let verdict = if score >= self.reject_threshold {
+            SpamVerdict::Reject
+        } else if score >= self.hold_threshold {
+            SpamVerdict::Hold
+        } else {
+            SpamVerdict::Accept
+        };
+
+        Ok(SpamCheckReport { verdict, reasons })
+    }
+
+    pub fn invalidate(&self) {
+        for filter in self.filters.iter() {
+            filter.invalidate();
+        }
+    }
+}
+
+pub struct LinkCountFilter {
+    pub max_links: usize,
+}
+
+impl SpamFilter for LinkCountFilter {
+    fn name(&self) -> &'static str {
+        "link_count"
+    }
+
+    fn evaluate(&self, _: &MainDbPooledConnection, target: &SpamCheckTarget) -> AppResult<FilterResult> {
+        let lowered = target.content.to_lowercase();
+        // `www.` right after a scheme belongs to a link counted already
+        let links = lowered.matches("http://").count()
+            + lowered.matches("https://").count()
+            + lowered
+                .match_indices("www.")
+                .filter(|(i, _)| !lowered[..*i].ends_with("://"))
+                .count();
+
+        if links <= self.max_links {
+            return Ok(FilterResult::clean());
+        }
+
+        Ok(FilterResult {
+            score: 1.0,
+            reason: Some(format!(
+                "contains {} links (limit: {})",
+                links, self.max_links
+            )),
+        })
+    }
+}
+
+pub struct BannedWordsFilter {
+    pub words: Vec<String>,
+}
+
+impl SpamFilter for BannedWordsFilter {
+    fn name(&self) -> &'static str {
+        "banned_words"
+    }
+
+    fn evaluate(&self, _: &MainDbPooledConnection, target: &SpamCheckTarget) -> AppResult<FilterResult> {
+        let haystack = format!(
+            "{}\n{}\n{}\n{}",
+            target.display_name,
+            target.site_url.unwrap_or(""),
+            target.mail_addr.unwrap_or(""),
+            target.content
+        )
+        .to_lowercase();
+
+        let hits: Vec<&str> = self
+            .words
+            .iter()
+            .filter(|word| haystack.contains(word.as_str()))
+            .map(|word| word.as_str())
+            .collect();
+
+        if hits.is_empty() {
+            return Ok(FilterResult::clean());
+        }
+
+        Ok(FilterResult {
+            score: hits.len() as f64,
+            reason: Some(format!("contains banned words: {}", hits.join(", "))),
+        })
+    }
+}
+
+pub struct IpRateFilter {
+    pub window: Duration,
+    pub max_comments: i64,
+}
+
+impl SpamFilter for IpRateFilter {
+    fn name(&self) -> &'static str {
+        "ip_rate"
+    }
+
+    fn evaluate(
+        &self,
+        conn: &MainDbPooledConnection,
+        target: &SpamCheckTarget,
+    ) -> AppResult<FilterResult> {
+        let posted = sql_query(
+            r#"
+                select count(*) from comments
+                where ip_addr = $1 and created_time > $2
+            "#,
+        )
+        .bind::<Inet, _>(target.ip_addr)
+        .bind::<Timestamptz, _>(Utc::now() - self.window)
+        .get_result::<CountResult>(conn)?
+        .count;
+
+        if posted < self.max_comments {
+            return Ok(FilterResult::clean());
+        }
+
+        Ok(FilterResult {
+            score: 1.0,
+            reason: Some(format!(
+                "{} comments from the same address within {} seconds",
+                posted + 1,
+                self.window.num_seconds()
+            )),
+        })
+    }
+}
+
+pub struct HoneypotFilter;
+
+impl SpamFilter for HoneypotFilter {
+    fn name(&self) -> &'static str {
+        "honeypot"
+    }
+
+    fn evaluate(&self, _: &MainDbPooledConnection, target: &SpamCheckTarget) -> AppResult<FilterResult> {
+        match target.honeypot {
+            Some(v) if !v.is_empty() => Ok(FilterResult {
+                score: 10.0,
+                reason: Some("filled in the honeypot field".to_string()),
+            }),
+            _ => Ok(FilterResult::clean()),
+        }
+    }
+}
+
+const BAYES_TRAINING_LIMIT: i64 = 2000;
+const BAYES_MIN_DOCUMENTS: usize = 10;
+
+struct BayesModel {
+    spam_tokens: HashMap<String, u32>,
+    ham_tokens: HashMap<String, u32>,
+    spam_total: u32,
+    ham_total: u32,
+    spam_docs: usize,
+    ham_docs: usize,
+    vocabulary: usize,
+}
+
+impl BayesModel {
+    fn train(spam: &[String], ham: &[String]) -> Self {
+        let mut spam_tokens = HashMap::new();
+        let mut ham_tokens = HashMap::new();
+
+        for doc in spam.iter() {
+            for token in tokenize(doc) {
+                *spam_tokens.entry(token).or_insert(0) += 1;
+            }
+        }
+        for doc in ham.iter() {
+            for token in tokenize(doc) {
+                *ham_tokens.entry(token).or_insert(0) += 1;
+            }
+        }
+
+        let vocabulary = spam_tokens
+            .keys()
+            .chain(ham_tokens.keys())
+            .collect::<std::collections::HashSet<_>>()
+            .len();
+
+        BayesModel {
+            spam_total: spam_tokens.values().sum(),
+            ham_total: ham_tokens.values().sum(),
+            spam_tokens,
+            ham_tokens,
+            spam_docs: spam.len(),
+            ham_docs: ham.len(),
+            vocabulary,
+        }
+    }
+
+    fn spam_probability(&self, text: &str) -> f64 {
+        let total_docs = (self.spam_docs + self.ham_docs) as f64;
+        let mut spam_log = (self.spam_docs as f64 / total_docs).ln();
+        let mut ham_log = (self.ham_docs as f64 / total_docs).ln();
+        let vocabulary = self.vocabulary as f64;
+
+        for token in tokenize(text) {
+            let in_spam = *self.spam_tokens.get(&token).unwrap_or(&0) as f64;
+            let in_ham = *self.ham_tokens.get(&token).unwrap_or(&0) as f64;
+            spam_log += ((in_spam + 1.0) / (self.spam_total as f64 + vocabulary)).ln();
+            ham_log += ((in_ham + 1.0) / (self.ham_total as f64 + vocabulary)).ln();
+        }
+
+        1.0 / (1.0 + (ham_log - spam_log).exp())
+    }
+}
+
+/// Splits on anything that is not alphanumeric. Runs of non-ASCII text
+/// (e.g. Japanese, which has no spaces) are split into character bigrams.
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+
+    for word in text
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+    {
+        if word.is_ascii() {
+            if word.len() >= 2 && word.len() <= 32 {
+                tokens.push(word.to_string());
+            }
+        } else {
+            let chars: Vec<char> = word.chars().collect();
+            if chars.len() == 1 {
+                tokens.push(word.to_string());
+            }
+            for pair in chars.windows(2) {
+                tokens.push(pair.iter().collect());
+            }
+        }
+    }
+
+    tokens
+}
+
+/// Learns from comments flagged as spam through `mark_comment` versus
+/// approved comments which were left alone.
+#[derive(Default)]
+pub struct BayesFilter {
+    model: RwLock<Option<BayesModel>>,
+}
+
+impl BayesFilter {
+    fn load_model(&self, conn: &MainDbPooledConnection) -> AppResult<()> {
+        let spam = comments::table
+            .select(comments::content)
+            .filter(sql::<Bool>(&format!(
+                "flags & {} = {}",
+                MARK_AS_SPAM_FRAG_BIT | MARK_AS_DELETED_FRAG_BIT,
+                MARK_AS_SPAM_FRAG_BIT
+            )))
+            .order(comments::created_time.desc())
+            .limit(BAYES_TRAINING_LIMIT)
+            .load::<String>(conn)?;
+        let ham = comments::table
+            .select(comments::content)
+            .filter(sql::<Bool>(&format!(
+                "flags & {} = 0",
+                MARK_AS_SPAM_FRAG_BIT | MARK_AS_DELETED_FRAG_BIT
+            )))
+            .filter(comments::status.eq(CommentStatus::Approved))
+            .order(comments::created_time.desc())
+            .limit(BAYES_TRAINING_LIMIT)
+            .load::<String>(conn)?;
+
+        let model = BayesModel::train(&spam, &ham);
+        *self.model.write().unwrap() = Some(model);
+
+        Ok(())
+    }
+}
+
+impl SpamFilter for BayesFilter {
+    fn name(&self) -> &'static str {
+        "bayes"
+    }
+
+    fn evaluate(
+        &self,
+        conn: &MainDbPooledConnection,
+        target: &SpamCheckTarget,
+    ) -> AppResult<FilterResult> {
+        if self.model.read().unwrap().is_none() {
+            self.load_model(conn)?;
+        }
+
+        let model = self.model.read().unwrap();
+        let model = match model.as_ref() {
+            Some(m) if m.spam_docs >= BAYES_MIN_DOCUMENTS && m.ham_docs >= BAYES_MIN_DOCUMENTS => m,
+            _ => return Ok(FilterResult::clean()),
+        };
+
+        let probability = model.spam_probability(target.content);
+        if probability < 0.5 {
+            return Ok(FilterResult::clean());
+        }
+
+        // 75% reaches the hold threshold, certainty reaches the reject threshold
+        Ok(FilterResult {
+            score: (probability - 0.5) * 4.0,
+            reason: Some(format!("spam probability {:.0}%", probability * 100.0)),
+        })
+    }
+
+    fn invalidate(&self) {
+        *self.model.write().unwrap() = None;
+    }
+}
+
+pub fn make_spam_filter_pipeline() -> SpamFilterPipeline {
+    let banned_words: Vec<String> = env::var("SPAM_BANNED_WORDS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|w| w.trim().to_lowercase())
+        .filter(|w| !w.is_empty())
+        .collect();
+
+    SpamFilterPipeline::new(
+        env_or("SPAM_HOLD_THRESHOLD", 1.0),
+        env_or("SPAM_REJECT_THRESHOLD", 2.0),
+    )
+    .with_filter(HoneypotFilter)
+    .with_filter(LinkCountFilter {
+        max_links: env_or("SPAM_MAX_LINKS", 3),
+    })
+    .with_filter(BannedWordsFilter {
+        words: banned_words,
+    })
+    .with_filter(IpRateFilter {
+        window: Duration::seconds(env_or("SPAM_RATE_WINDOW_SECS", 600)),
+        max_comments: env_or("SPAM_RATE_MAX_COMMENTS", 5),
+    })
+    .with_filter(BayesFilter::default())
+}
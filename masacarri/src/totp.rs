@@ -0,0 +1,311 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::auth::AuthUser;
+use crate::db::{MainDbConnection, Pool};
+use crate::error::{AppError, AppResult};
+use crate::models::User;
+use crate::schema::{recovery_codes, users};
+use crate::utils::sha256_hex;
+use actix_web::{web, HttpResponse, Responder};
+use chrono::Utc;
+use diesel::prelude::*;
+use hmac::{Hmac, Mac};
+use rand::distributions::{Alphanumeric, DistString};
+use rand::RngCore;
+use serde::Deserialize;
+use serde_json::json;
+use sha1::Sha1;
+
+const TOTP_STEP_SECS: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// Steps accepted before and after the current one, for clock drift.
+const TOTP_SKEW_STEPS: i64 = 1;
+const TOTP_SECRET_BYTES: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LENGTH: usize = 12;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+#[derive(Insertable)]
+#[table_name = "recovery_codes"]
+pub struct NewRecoveryCode {
+    id: uuid::Uuid,
+    user_id: uuid::Uuid,
+    code_hash: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfirmTotpRequest {
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct DisableTotpRequest {
+    password: String,
+}
+
+/// RFC 4648 base32 without padding, as used by authenticator apps.
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in data.chunks(5) {
+        let mut buf = [0u8; 5];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let bits = buf.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            encoded.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut bits: u64 = 0;
+    let mut bit_count = 0;
+    for c in encoded.chars().filter(|c| *c != '=' && !c.is_whitespace()) {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|a| *a as char == c.to_ascii_uppercase())?;
+        bits = (bits << 5) | value as u64;
+        bit_count += 5;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            decoded.push((bits >> bit_count) as u8);
+            bits &= (1 << bit_count) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+fn current_step() -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    (now / TOTP_STEP_SECS) as i64
+}
+
+/// RFC 6238 with HMAC-SHA1, as every authenticator app supports it.
+fn totp_code(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts any key length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    truncated % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Returns the matched step, which must be stored to refuse the same code twice.
+fn verify_totp(secret_b32: &str, code: &str, last_step: Option<i64>) -> Option<i64> {
+    let secret = base32_decode(secret_b32)?;
+    let code: u32 = code.trim().replace(' ', "").parse().ok()?;
+    let step_now = current_step();
+
+    (step_now - TOTP_SKEW_STEPS..=step_now + TOTP_SKEW_STEPS)
+        .filter(|step| last_step.is_none_or(|last| *step > last))
+        .find(|step| totp_code(&secret, *step) == code)
+}
+
+fn hash_recovery_code(code: &str) -> String {
+    let normalized = code.trim().replace('-', "").to_ascii_lowercase();
+    sha256_hex(&normalized)
+}
+
+pub fn is_totp_enabled(user: &User) -> bool {
+    user.totp_secret.is_some()
+}
+
+/// Accepts either a TOTP code or an unused recovery code.
+pub fn verify_second_factor(conn: &MainDbConnection, user: &User, code: &str) -> AppResult<bool> {
+    let secret = match &user.totp_secret {
+        Some(secret) => secret,
+        None => return Ok(false),
+    };
+
+    if let Some(step) = verify_totp(secret, code, user.totp_last_step) {
+        let updated = diesel::update(
+            users::table
+                .find(user.id)
+                .filter(users::totp_last_step.is_null().or(users::totp_last_step.lt(step))),
+        )
+        .set(users::totp_last_step.eq(step))
+        .execute(conn)?;
+        return Ok(updated == 1);
+    }
+
+    let used = diesel::update(
+        recovery_codes::table
+            .filter(recovery_codes::user_id.eq(user.id))
+            .filter(recovery_codes::code_hash.eq(hash_recovery_code(code)))
+            .filter(recovery_codes::used_time.is_null()),
+    )
+    .set(recovery_codes::used_time.eq(Utc::now()))
+    .execute(conn)?;
+
+    Ok(used > 0)
+}
+
+/// Removes the second factor, for users who lost their device and recovery codes.
+pub fn reset_totp(conn: &MainDbConnection, tgt_user_id: uuid::Uuid) -> QueryResult<()> {
+    conn.transaction(|| {
+        diesel::update(users::table.find(tgt_user_id))
+            .set((
+                users::totp_secret.eq(None::<String>),
+                users::totp_pending_secret.eq(None::<String>),
+                users::totp_last_step.eq(None::<i64>),
+            ))
+            .execute(conn)?;
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(tgt_user_id)))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
+/// A new secret would replace the second factor, which must be turned off
+/// with the password first.
+fn chk_totp_not_enabled(user: &User) -> AppResult<()> {
+    if is_totp_enabled(user) {
+        return Err(AppError::PublishableErr(
+            "Two-factor authentication is already on. Turn it off first.".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Starts enrollment. The secret takes effect once a code from it is confirmed.
+pub async fn begin_totp_enrollment(
+    user: AuthUser,
+    db: web::Data<Pool>,
+) -> AppResult<impl Responder> {
+    user.require_session()?;
+
+    let conn = db.get()?;
+
+    let tgt_user = users::table.find(user.id).first::<User>(&conn)?;
+    chk_totp_not_enabled(&tgt_user)?;
+
+    let mut secret = [0u8; TOTP_SECRET_BYTES];
+    rand::thread_rng().fill_bytes(&mut secret);
+    let secret = base32_encode(&secret);
+
+    diesel::update(users::table.find(user.id))
+        .set(users::totp_pending_secret.eq(&secret))
+        .execute(&conn)?;
+
+    let issuer = "Masacarri";
+    let otpauth_url = url::Url::parse_with_params(
+        &format!("otpauth://totp/{}:{}", issuer, user.username),
+        &[
+            ("secret", secret.as_str()),
+            ("issuer", issuer),
+            ("digits", &TOTP_DIGITS.to_string()),
+            ("period", &TOTP_STEP_SECS.to_string()),
+        ],
+    )
+    .map_err(|_| AppError::UnspecifiedErr)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "secret": secret,
+        "otpauth_url": otpauth_url.to_string(),
+    })))
+}
+
+/// Enables TOTP and returns the recovery codes, which are shown only this once.
+pub async fn confirm_totp_enrollment(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    confirm_request: web::Json<ConfirmTotpRequest>,
+) -> AppResult<impl Responder> {
+    user.require_session()?;
+
+    let conn = db.get()?;
+
+    let tgt_user = users::table.find(user.id).first::<User>(&conn)?;
+    chk_totp_not_enabled(&tgt_user)?;
+
+    let pending_secret = tgt_user.totp_pending_secret.ok_or_else(|| {
+        AppError::PublishableErr("Start the enrollment first.".to_string())
+    })?;
+
+    let step = verify_totp(&pending_secret, &confirm_request.code, None)
+        .ok_or_else(|| AppError::PublishableErr("The code is incorrect.".to_string()))?;
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            Alphanumeric
+                .sample_string(&mut rand::thread_rng(), RECOVERY_CODE_LENGTH)
+                .to_ascii_lowercase()
+        })
+        .collect();
+
+    conn.transaction::<_, AppError, _>(|| {
+        let updated = diesel::update(
+            users::table
+                .find(user.id)
+                .filter(users::totp_secret.is_null())
+                .filter(users::totp_pending_secret.eq(&pending_secret)),
+        )
+        .set((
+            users::totp_secret.eq(Some(&pending_secret)),
+            users::totp_pending_secret.eq(None::<String>),
+            users::totp_last_step.eq(step),
+        ))
+        .execute(&conn)?;
+        if updated == 0 {
+            return Err(AppError::PublishableErr(
+                "Start the enrollment first.".to_string(),
+            ));
+        }
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(user.id)))
+            .execute(&conn)?;
+        let new_codes: Vec<NewRecoveryCode> = codes
+            .iter()
+            .map(|code| NewRecoveryCode {
+                id: uuid::Uuid::new_v4(),
+                user_id: user.id,
+                code_hash: hash_recovery_code(code),
+            })
+            .collect();
+        diesel::insert_into(recovery_codes::table)
+            .values(&new_codes)
+            .execute(&conn)?;
+        Ok(())
+    })?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "recovery_codes": codes,
+    })))
+}
+
+pub async fn disable_totp(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    disable_request: web::Json<DisableTotpRequest>,
+) -> AppResult<impl Responder> {
+    user.require_session()?;
+
+    let conn = db.get()?;
+
+    let current_hash: String = users::table
+        .find(user.id)
+        .select(users::password_hash)
+        .first(&conn)?;
+
+    if !bcrypt::verify(&disable_request.password, &current_hash).unwrap_or(false) {
+        return Err(AppError::ForbiddenErr(
+            "The current password is incorrect.".to_string(),
+        ));
+    }
+
+    reset_totp(&conn, user.id)?;
+
+    Ok(HttpResponse::NoContent())
+}
@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::auth::{AuthUser, Permission};
+use crate::db::{MainDbConnection, Pool};
+use crate::error::{AppError, AppResult};
+use crate::models::{CountResult, Role, User};
+use crate::schema::{site_users, sites, users};
+use actix_web::{web, HttpResponse, Responder};
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{self, Text};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+pub const USER_DISABLED_FLAG_BIT: i32 = 1;
+
+const PASSWORD_HASH_COST: u32 = 8;
+const MIN_PASSWORD_LENGTH: usize = 8;
+const TEMPORARY_PASSWORD_LENGTH: usize = 16;
+const MAX_DISPLAY_NAME_LENGTH: usize = 256;
+
+#[derive(Insertable)]
+#[table_name = "users"]
+pub struct NewUser {
+    id: uuid::Uuid,
+    username: String,
+    password_hash: String,
+    flags: i32,
+    role: Role,
+}
+
+#[derive(Insertable)]
+#[table_name = "site_users"]
+pub struct NewSiteUser {
+    site_id: uuid::Uuid,
+    user_id: uuid::Uuid,
+}
+
+#[derive(Serialize)]
+pub struct GetUserResponse {
+    id: uuid::Uuid,
+    username: String,
+    display_name: Option<String>,
+    role: Role,
+    disabled: bool,
+    sites: Vec<uuid::Uuid>,
+}
+
+#[derive(Deserialize)]
+pub struct InviteUserRequest {
+    username: String,
+    role: Role,
+    #[serde(default)]
+    sites: Vec<uuid::Uuid>,
+}
+
+#[derive(Deserialize)]
+pub struct ModifyUserRequestPath {
+    user: uuid::Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct ModifyUserRequest {
+    role: Option<Role>,
+    disabled: Option<bool>,
+    sites: Option<Vec<uuid::Uuid>>,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteUserRequestPath {
+    user: uuid::Uuid,
+}
+
+#[derive(Deserialize)]
+pub struct ModifyOwnProfileRequest {
+    display_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+pub fn is_user_disabled(user: &User) -> bool {
+    (user.flags & USER_DISABLED_FLAG_BIT) == USER_DISABLED_FLAG_BIT
+}
+
+pub fn hash_password(password: &str) -> AppResult<String> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(AppError::PublishableErr(format!(
+            "Password must be at least {} characters.",
+            MIN_PASSWORD_LENGTH
+        )));
+    }
+
+    Ok(bcrypt::hash(password, PASSWORD_HASH_COST)?)
+}
+
+/// Verified against when the user does not exist, so that the lookup takes as long as a real one.
+pub fn dummy_password_hash() -> &'static str {
+    static DUMMY_PASSWORD_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_PASSWORD_HASH.get_or_init(|| {
+        bcrypt::hash(uuid::Uuid::new_v4().to_string(), PASSWORD_HASH_COST).unwrap()
+    })
+}
+
+fn make_user_response(user: User, sites_of_user: &mut HashMap<uuid::Uuid, Vec<uuid::Uuid>>) -> GetUserResponse {
+    GetUserResponse {
+        disabled: is_user_disabled(&user),
+        sites: sites_of_user.remove(&user.id).unwrap_or_default(),
+        id: user.id,
+        username: user.username,
+        display_name: user.display_name,
+        role: user.role,
+    }
+}
+
+/// The name shown on comments the user posts as the author.
+pub fn author_name(user: &User) -> String {
+    user.display_name
+        .clone()
+        .unwrap_or_else(|| user.username.clone())
+}
+
+/// Whether `name` looks like a name an author of the page's site is shown under, ignoring case
+/// and spaces, so that other commenters cannot pass themselves off as one.
+pub fn is_name_reserved(
+    conn: &MainDbConnection,
+    tgt_page_id: uuid::Uuid,
+    name: &str,
+) -> AppResult<bool> {
+    // usernames are never compared: only display names, and the names authors have been shown
+    // under on comments, which are public anyway
+    let matched = sql_query(
+        r#"
+            with authors as (
+                select users.id, users.display_name from users
+                where users.role = 'owner'
+                or users.id in (
+                    select site_users.user_id from site_users
+                    inner join pages on pages.site_id = site_users.site_id
+                    where pages.id = $1
+                )
+            ),
+            author_names as (
+                select authors.display_name as name from authors
+                where authors.display_name is not null
+                union all
+                select comments.display_name as name from comments
+                inner join authors on authors.id = comments.author_user_id
+            )
+            select count(*) from author_names
+            where regexp_replace(lower(author_names.name), '\s', '', 'g')
+                = regexp_replace(lower($2), '\s', '', 'g')
+        "#,
+    )
+    .bind::<sql_types::Uuid, _>(tgt_page_id)
+    .bind::<Text, _>(name)
+    .get_result::<CountResult>(conn)?
+    .count;
+
+    Ok(matched > 0)
+}
+
+fn load_site_memberships(
+    conn: &MainDbConnection,
+    user_ids: &[uuid::Uuid],
+) -> AppResult<HashMap<uuid::Uuid, Vec<uuid::Uuid>>> {
+    let mut result: HashMap<uuid::Uuid, Vec<uuid::Uuid>> = HashMap::new();
+    for (r_user_id, r_site_id) in site_users::table
+        .filter(site_users::user_id.eq_any(user_ids))
+        .select((site_users::user_id, site_users::site_id))
+        .load::<(uuid::Uuid, uuid::Uuid)>(conn)?
+    {
+        result.entry(r_user_id).or_default().push(r_site_id);
+    }
+    Ok(result)
+}
+
+fn set_site_memberships(
+    conn: &MainDbConnection,
+    tgt_user_id: uuid::Uuid,
+    site_ids: &[uuid::Uuid],
+) -> AppResult<()> {
+    let found: i64 = sites::table
+        .filter(sites::id.eq_any(site_ids))
+        .count()
+        .get_result(conn)?;
+    if found as usize != site_ids.len() {
+        return Err(AppError::NotFoundErr("site not found".to_string()));
+    }
+
+    diesel::delete(site_users::table.filter(site_users::user_id.eq(tgt_user_id))).execute(conn)?;
+    let new_memberships: Vec<NewSiteUser> = site_ids
+        .iter()
+        .map(|r_site_id| NewSiteUser {
+            site_id: *r_site_id,
+            user_id: tgt_user_id,
+        })
+        .collect();
+    diesel::insert_into(site_users::table)
+        .values(&new_memberships)
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+
+    Ok(())
+}
+
+/// Refuses changes which would leave nobody able to manage users.
+/// The owner rows stay locked until the caller's transaction ends, so that
+/// two owners cannot remove each other at the same time.
+fn chk_other_owner_remains(conn: &MainDbConnection, tgt_user_id: uuid::Uuid) -> AppResult<()> {
+    let owners = users::table
+        .select((users::id, users::flags))
+        .filter(users::role.eq(Role::Owner))
+        .order(users::id)
+        .for_update()
+        .load::<(uuid::Uuid, i32)>(conn)?;
+
+    let other_owners = owners
+        .iter()
+        .filter(|(owner_id, owner_flags)| {
+            *owner_id != tgt_user_id && owner_flags & USER_DISABLED_FLAG_BIT == 0
+        })
+        .count();
+
+    if other_owners == 0 {
+        return Err(AppError::PublishableErr(
+            "The last owner cannot be removed.".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn chk_not_self(user: &AuthUser, tgt_user_id: uuid::Uuid) -> AppResult<()> {
+    if user.id == tgt_user_id {
+        return Err(AppError::PublishableErr(
+            "You cannot do this to your own account.".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+pub async fn get_users(user: AuthUser, db: web::Data<Pool>) -> AppResult<impl Responder> {
+    user.require(Permission::ManageUsers)?;
+
+    let conn = db.get()?;
+
+    let result = users::table.order(users::username).load::<User>(&conn)?;
+    let user_ids: Vec<uuid::Uuid> = result.iter().map(|u| u.id).collect();
+    let mut sites_of_user = load_site_memberships(&conn, &user_ids)?;
+
+    let result: Vec<GetUserResponse> = result
+        .into_iter()
+        .map(|u| make_user_response(u, &mut sites_of_user))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(result))
+}
+
+/// Creates the account with a temporary password, which is returned only once.
+pub async fn invite_user(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    new_user: web::Json<InviteUserRequest>,
+) -> AppResult<impl Responder> {
+    user.require(Permission::ManageUsers)?;
+
+    let conn = db.get()?;
+
+    let InviteUserRequest {
+        username: r_username,
+        role: r_role,
+        sites: r_sites,
+    } = new_user.into_inner();
+
+    let r_username = r_username.trim().to_string();
+    if r_username.is_empty() {
+        return Err(AppError::PublishableErr("Username is required.".to_string()));
+    }
+
+    let temporary_password =
+        Alphanumeric.sample_string(&mut rand::thread_rng(), TEMPORARY_PASSWORD_LENGTH);
+    let new_id = uuid::Uuid::new_v4();
+    let new_password_hash = hash_password(&temporary_password)?;
+
+    conn.transaction::<_, AppError, _>(|| {
+        let res = diesel::insert_into(users::table)
+            .values(NewUser {
+                id: new_id,
+                username: r_username,
+                password_hash: new_password_hash,
+                flags: 0,
+                role: r_role,
+            })
+            .execute(&conn);
+        if res.is_err() {
+            return Err(AppError::PublishableErr(
+                "Failed to create the user. The username may already be used.".to_owned(),
+            ));
+        }
+        set_site_memberships(&conn, new_id, &r_sites)
+    })?;
+
+    let created = users::table.find(new_id).first::<User>(&conn)?;
+    let mut sites_of_user = load_site_memberships(&conn, &[new_id])?;
+
+    Ok(HttpResponse::Created().json(json!({
+        "user": make_user_response(created, &mut sites_of_user),
+        "temporary_password": temporary_password,
+    })))
+}
+
+pub async fn modify_user(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    path_param: web::Path<ModifyUserRequestPath>,
+    updated_user: web::Json<ModifyUserRequest>,
+) -> AppResult<impl Responder> {
+    user.require(Permission::ManageUsers)?;
+
+    let conn = db.get()?;
+
+    let tgt_user = users::table
+        .find(path_param.user)
+        .first::<User>(&conn)
+        .optional()?
+        .ok_or_else(|| AppError::NotFoundErr("user not found".to_string()))?;
+
+    let ModifyUserRequest {
+        role: r_role,
+        disabled: r_disabled,
+        sites: r_sites,
+    } = updated_user.into_inner();
+
+    let loses_owner = tgt_user.role == Role::Owner
+        && (r_role.is_some_and(|v| v != Role::Owner) || r_disabled == Some(true));
+    if r_role.is_some() || r_disabled.is_some() {
+        chk_not_self(&user, tgt_user.id)?;
+    }
+
+    conn.transaction::<_, AppError, _>(|| {
+        if loses_owner {
+            chk_other_owner_remains(&conn, tgt_user.id)?;
+        }
+        if let Some(r_role) = r_role {
+            diesel::update(users::table.find(tgt_user.id))
+                .set(users::role.eq(r_role))
+                .execute(&conn)?;
+        }
+        if let Some(r_disabled) = r_disabled {
+            let new_flags = if r_disabled {
+                tgt_user.flags | USER_DISABLED_FLAG_BIT
+            } else {
+                tgt_user.flags & !USER_DISABLED_FLAG_BIT
+            };
+            diesel::update(users::table.find(tgt_user.id))
+                .set(users::flags.eq(new_flags))
+                .execute(&conn)?;
+        }
+        if let Some(r_sites) = r_sites {
+            set_site_memberships(&conn, tgt_user.id, &r_sites)?;
+        }
+        Ok(())
+    })?;
+
+    Ok(HttpResponse::NoContent())
+}
+
+pub async fn delete_user(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    path_param: web::Path<DeleteUserRequestPath>,
+) -> AppResult<impl Responder> {
+    user.require(Permission::ManageUsers)?;
+    chk_not_self(&user, path_param.user)?;
+
+    let conn = db.get()?;
+
+    conn.transaction::<_, AppError, _>(|| {
+        let tgt_user = users::table
+            .find(path_param.user)
+            .first::<User>(&conn)
+            .optional()?
+            .ok_or_else(|| AppError::NotFoundErr("user not found".to_string()))?;
+
+        if tgt_user.role == Role::Owner {
+            chk_other_owner_remains(&conn, tgt_user.id)?;
+        }
+
+        diesel::delete(users::table.find(tgt_user.id)).execute(&conn)?;
+        Ok(())
+    })?;
+
+    Ok(HttpResponse::NoContent())
+}
+
+pub async fn modify_own_profile(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    updated_profile: web::Json<ModifyOwnProfileRequest>,
+) -> AppResult<impl Responder> {
+    user.require_session()?;
+
+    let conn = db.get()?;
+
+    let r_display_name = updated_profile
+        .into_inner()
+        .display_name
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+    if r_display_name
+        .as_ref()
+        .is_some_and(|v| v.chars().count() > MAX_DISPLAY_NAME_LENGTH)
+    {
+        return Err(AppError::PublishableErr(format!(
+            "Display name is limited up to {} characters.",
+            MAX_DISPLAY_NAME_LENGTH
+        )));
+    }
+
+    diesel::update(users::table.find(user.id))
+        .set(users::display_name.eq(r_display_name))
+        .execute(&conn)?;
+
+    Ok(HttpResponse::NoContent())
+}
+
+pub async fn change_own_password(
+    user: AuthUser,
+    db: web::Data<Pool>,
+    change_request: web::Json<ChangePasswordRequest>,
+) -> AppResult<impl Responder> {
+    user.require_session()?;
+
+    let conn = db.get()?;
+
+    let current_hash: String = users::table
+        .find(user.id)
+        .select(users::password_hash)
+        .first(&conn)?;
+
+    if !bcrypt::verify(&change_request.current_password, &current_hash).unwrap_or(false) {
+        return Err(AppError::ForbiddenErr(
+            "The current password is incorrect.".to_string(),
+        ));
+    }
+
+    let new_hash = hash_password(&change_request.new_password)?;
+
+    diesel::update(users::table.find(user.id))
+        .set(users::password_hash.eq(new_hash))
+        .execute(&conn)?;
+
+    Ok(HttpResponse::NoContent())
+}
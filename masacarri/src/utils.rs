@@ -1,3 +1,8 @@
+use std::env;
+use std::str::FromStr;
+
+use sha2::{Digest, Sha256};
+
 pub fn empty_to_none(s: Option<String>) -> Option<String> {
     match s {
         Some(v) if v.is_empty() => None,
@@ -5,3 +10,35 @@ pub fn empty_to_none(s: Option<String>) -> Option<String> {
         None => None,
     }
 }
+
+pub fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match env::var(key) {
+        Ok(v) if !v.is_empty() => v
+            .parse::<T>()
+            .unwrap_or_else(|_| panic!("{} is invalid", key)),
+        _ => default,
+    }
+}
+
+pub fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// For secrets which are stored only as a hash, such as recovery codes and API tokens.
+pub fn sha256_hex(data: &str) -> String {
+    Sha256::digest(data.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
@@ -0,0 +1,308 @@
+//! Drives the commenter sign-in against a mock OpenID Connect provider on localhost.
+//! None of the refused sign-ins reaches the database, so no database is needed.
+
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex, Once};
+
+use actix_session::storage::CookieSessionStore;
+use actix_session::SessionMiddleware;
+use actix_web::cookie::{Cookie, Key};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::{header, StatusCode};
+use actix_web::{test, web, App, HttpResponse, HttpServer};
+use chrono::Utc;
+use diesel::r2d2::ConnectionManager;
+use masacarri::commenter::{begin_commenter_login, finish_commenter_login, OidcProvider};
+use masacarri::db::{MainDbConnection, Pool};
+use serde_json::{json, Value};
+
+const CLIENT_ID: &str = "masacarri-test";
+const REDIRECT_URL: &str = "http://localhost/api/commenter/callback";
+
+static DEVELOPMENT_MODE: Once = Once::new();
+
+/// A provider which answers every token request with the claims a test put in `claims`.
+struct MockIssuer {
+    issuer: String,
+    claims: Arc<Mutex<Value>>,
+}
+
+fn encode_segment(value: &Value) -> String {
+    base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD)
+}
+
+fn start_mock_issuer() -> MockIssuer {
+    // the mock provider speaks plain http
+    DEVELOPMENT_MODE.call_once(|| std::env::set_var("MODE", "development"));
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let issuer = format!("http://{}", listener.local_addr().unwrap());
+    let claims = Arc::new(Mutex::new(Value::Null));
+
+    let discovery = json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/authorize", issuer),
+        "token_endpoint": format!("{}/token", issuer),
+    });
+    let token_claims = claims.clone();
+    let server = HttpServer::new(move || {
+        let discovery = discovery.clone();
+        let token_claims = token_claims.clone();
+        App::new()
+            .route(
+                "/.well-known/openid-configuration",
+                web::get().to(move || {
+                    let discovery = discovery.clone();
+                    async move { HttpResponse::Ok().json(discovery) }
+                }),
+            )
+            .route(
+                "/token",
+                web::post().to(move || {
+                    let claims = token_claims.lock().unwrap().clone();
+                    async move {
+                        let id_token = format!(
+                            "{}.{}.",
+                            encode_segment(&json!({ "alg": "none" })),
+                            encode_segment(&claims)
+                        );
+                        HttpResponse::Ok().json(json!({
+                            "access_token": "mock-access-token",
+                            "token_type": "Bearer",
+                            "id_token": id_token,
+                        }))
+                    }
+                }),
+            )
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    actix_web::rt::spawn(server);
+
+    MockIssuer { issuer, claims }
+}
+
+fn valid_claims(issuer: &str, nonce: &str) -> Value {
+    json!({
+        "iss": issuer,
+        "aud": CLIENT_ID,
+        "sub": "mock-subject",
+        "exp": Utc::now().timestamp() + 300,
+        "nonce": nonce,
+        "name": "Mock Commenter",
+    })
+}
+
+/// Never connects, as refused sign-ins end before the database is used.
+fn unused_pool() -> Pool {
+    Pool::builder()
+        .min_idle(Some(0))
+        .build_unchecked(ConnectionManager::<MainDbConnection>::new(
+            "postgres://localhost/unused",
+        ))
+}
+
+struct SignIn {
+    location: url::Url,
+    state: String,
+    nonce: String,
+    cookies: Vec<Cookie<'static>>,
+}
+
+fn query_value(location: &url::Url, key: &str) -> String {
+    location
+        .query_pairs()
+        .find(|(k, _)| k == key)
+        .map(|(_, v)| v.to_string())
+        .unwrap_or_default()
+}
+
+/// Opens the sign-in popup, then comes back to the callback with `state` (the expected one
+/// unless given) after the provider was told to issue `claims(nonce)`.
+async fn sign_in(
+    mock: &MockIssuer,
+    state: Option<&str>,
+    claims: impl FnOnce(&str) -> Value,
+) -> (StatusCode, String) {
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(Some(OidcProvider::new(
+                &mock.issuer,
+                CLIENT_ID,
+                "mock-secret",
+                url::Url::parse(REDIRECT_URL).unwrap(),
+            ))))
+            .app_data(web::Data::new(unused_pool()))
+            .wrap(SessionMiddleware::new(
+                CookieSessionStore::default(),
+                Key::generate(),
+            ))
+            .route("/api/commenter/login", web::get().to(begin_commenter_login))
+            .route(
+                "/api/commenter/callback",
+                web::get().to(finish_commenter_login),
+            ),
+    )
+    .await;
+
+    let begun = read_redirect(&test::call_service(&app, begin_request().to_request()).await);
+    *mock.claims.lock().unwrap() = claims(&begun.nonce);
+
+    let mut callback = url::Url::parse(REDIRECT_URL).unwrap();
+    callback
+        .query_pairs_mut()
+        .append_pair("code", "mock-code")
+        .append_pair("state", state.unwrap_or(&begun.state));
+    let mut req = test::TestRequest::get().uri(&format!(
+        "{}?{}",
+        callback.path(),
+        callback.query().unwrap()
+    ));
+    for cookie in begun.cookies {
+        req = req.cookie(cookie);
+    }
+
+    let res = test::call_service(&app, req.to_request()).await;
+    let status = res.status();
+    let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    (status, body)
+}
+
+/// Reads the redirect to the provider which `begin_commenter_login` answered with.
+fn read_redirect<B>(res: &ServiceResponse<B>) -> SignIn {
+    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+
+    let location = res
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| url::Url::parse(v).ok())
+        .unwrap();
+    let cookies = res.response().cookies().map(|c| c.into_owned()).collect();
+
+    SignIn {
+        state: query_value(&location, "state"),
+        nonce: query_value(&location, "nonce"),
+        location,
+        cookies,
+    }
+}
+
+fn begin_request() -> test::TestRequest {
+    test::TestRequest::get().uri("/api/commenter/login")
+}
+
+#[actix_web::test]
+async fn begin_redirects_to_the_discovered_authorization_endpoint() {
+    let mock = start_mock_issuer();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(Some(OidcProvider::new(
+                &mock.issuer,
+                CLIENT_ID,
+                "mock-secret",
+                url::Url::parse(REDIRECT_URL).unwrap(),
+            ))))
+            .wrap(SessionMiddleware::new(
+                CookieSessionStore::default(),
+                Key::generate(),
+            ))
+            .route("/api/commenter/login", web::get().to(begin_commenter_login)),
+    )
+    .await;
+
+    let begun = read_redirect(&test::call_service(&app, begin_request().to_request()).await);
+
+    assert_eq!(
+        begun.location.as_str().split('?').next(),
+        Some(format!("{}/authorize", mock.issuer).as_str())
+    );
+    assert_eq!(query_value(&begun.location, "response_type"), "code");
+    assert_eq!(query_value(&begun.location, "client_id"), CLIENT_ID);
+    assert_eq!(query_value(&begun.location, "redirect_uri"), REDIRECT_URL);
+    assert!(!begun.state.is_empty());
+    assert!(!begun.nonce.is_empty());
+    assert_ne!(begun.state, begun.nonce);
+    assert!(!begun.cookies.is_empty());
+}
+
+#[actix_web::test]
+async fn state_mismatch_is_refused() {
+    let mock = start_mock_issuer();
+
+    let (status, body) = sign_in(&mock, Some("forged-state"), |nonce| {
+        valid_claims(&mock.issuer, nonce)
+    })
+    .await;
+
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body.contains("the state does not match"), "{}", body);
+}
+
+#[actix_web::test]
+async fn nonce_mismatch_is_refused() {
+    let mock = start_mock_issuer();
+
+    let (status, body) = sign_in(&mock, None, |_| {
+        valid_claims(&mock.issuer, "replayed-nonce")
+    })
+    .await;
+
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body.contains("the nonce does not match"), "{}", body);
+}
+
+#[actix_web::test]
+async fn token_for_another_client_is_refused() {
+    let mock = start_mock_issuer();
+
+    let (status, body) = sign_in(&mock, None, |nonce| {
+        let mut claims = valid_claims(&mock.issuer, nonce);
+        claims["aud"] = json!(["another-client"]);
+        claims
+    })
+    .await;
+
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(
+        body.contains("the ID token is for another client"),
+        "{}",
+        body
+    );
+}
+
+#[actix_web::test]
+async fn token_from_another_issuer_is_refused() {
+    let mock = start_mock_issuer();
+
+    let (status, body) = sign_in(&mock, None, |nonce| {
+        let mut claims = valid_claims(&mock.issuer, nonce);
+        claims["iss"] = json!("https://issuer.invalid");
+        claims
+    })
+    .await;
+
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(
+        body.contains("the ID token is from another issuer"),
+        "{}",
+        body
+    );
+}
+
+#[actix_web::test]
+async fn expired_token_is_refused() {
+    let mock = start_mock_issuer();
+
+    let (status, body) = sign_in(&mock, None, |nonce| {
+        let mut claims = valid_claims(&mock.issuer, nonce);
+        claims["exp"] = json!(Utc::now().timestamp() - 60);
+        claims
+    })
+    .await;
+
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(body.contains("the ID token has expired"), "{}", body);
+}